@@ -0,0 +1,689 @@
+use log::debug;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{error::CodexError, show_warning, Args};
+
+const DEFAULT_MODEL: &str = "llama3.2";
+const DEFAULT_CONTEXT_BUDGET: usize = 8000;
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_PORT: u16 = 8000;
+
+const CONFIG_TEMPLATE: &str = r#"# CodexCLI configuration
+#
+# Everything here is optional. Command-line flags override these values,
+# and CODEXCLI_* environment variables sit between the two.
+
+# Ollama model to use for responses
+# model = "llama3.2"
+
+# Working directory for code execution
+# workdir = "/path/to/scratch"
+
+# How responses are printed: "ansi" (highlighted and wrapped), "plain"
+# (verbatim), "markdown" (no ANSI codes, for piping into a renderer such as
+# glow) or "json" (one object per prompt)
+# format = "ansi"
+
+# Remember earlier turns of the conversation
+# context = true
+
+# Maximum characters of conversation history sent with each prompt
+# context_budget = 8000
+
+# "http" talks to the Ollama API, "cli" runs the ollama command. Unset uses
+# the API when the server answers and the CLI otherwise.
+# backend = "http"
+
+# Ollama server to talk to, if not the local one (OLLAMA_HOST works too).
+# Code blocks still run on this machine.
+# ollama_host = "http://gpu-box:11434"
+
+# Generation limits passed to Ollama (unset keeps the model's defaults)
+# max_tokens = 512
+# temperature = 0.8
+
+# Any other Ollama options (--model-option on the command line)
+# model_options = { seed = 42, top_p = 0.9 }
+
+# Files sent with every prompt, as globs relative to the current directory
+# context_files = ["src/**/*.rs", "Cargo.toml"]
+
+# Instructions sent ahead of every prompt
+# system_prompt = "You are a terse Rust expert."
+
+# Allow running code blocks from AI responses
+# allow_exec = false
+
+# Run code blocks without asking ("always"), ask first ("ask") or never
+# offer to run them ("never"). "always" still needs allow_exec.
+# auto_execute = "ask"
+
+# Kill code blocks that run longer than this many seconds (0 disables)
+# timeout = 30
+
+# Shell that runs !commands (defaults to $SHELL, or cmd on Windows)
+# shell = "bash"
+
+# SQLite database that sql blocks run against (in-memory when unset)
+# db = "scratch.db"
+
+# Port and listen address for start-server
+# port = 8000
+# bind = "127.0.0.1"
+
+# Append every prompt and response to ~/.config/codexcli/sessions/<date>.md
+# log = false
+
+# Number the lines of code blocks in responses
+# line_numbers = false
+
+# Show responses taller than the terminal in $PAGER (less -R by default)
+# pager = false
+
+# Show the time taken and tokens/s after each response
+# stats = false
+
+# Show the <think> reasoning of reasoning models, dimmed, instead of hiding it
+# show_thinking = false
+
+# Keep colors but drop the banner, dividers and emoji
+# minimal = false
+
+# Animated spinners (false keeps colors but draws spinners still)
+# animation = true
+
+# Path to a FIGlet font (.flf) for the startup banner
+# banner_font = "/usr/share/figlet/slant.flf"
+
+# Spinner animation: dots, line, arc, circle, arrows or bounce
+# spinner_style = "dots"
+
+# Color for the banner and spinners: black, red, green, yellow, blue,
+# magenta, cyan or white
+# accent_color = "green"
+
+# Named sets of the settings above, picked with --profile <name> (or
+# `!profile <name>` in a session) and laid over the rest of this file
+# [profiles.coding]
+# model = "codellama"
+# allow_exec = true
+#
+# [profiles.writing]
+# model = "llama3.2"
+# allow_exec = false
+"#;
+
+/// Project config file, looked up from the current directory upwards
+const PROJECT_CONFIG: &str = ".codexcli.toml";
+
+/// Whether code blocks in a response run without asking. Running at all
+/// still needs `allow_exec`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoExecute {
+    Never,
+    #[default]
+    Ask,
+    Always,
+}
+
+/// How requests reach Ollama
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Spawn `ollama run`
+    Cli,
+    /// The server's HTTP API
+    Http,
+}
+
+/// How responses are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// The model's output verbatim, without banners or spinners
+    Plain,
+    /// Highlighted, wrapped and decorated for a terminal
+    #[default]
+    Ansi,
+    /// The markdown untouched but free of ANSI codes, with `\n` line endings
+    Markdown,
+    /// One JSON object per prompt
+    Json,
+}
+
+/// Settings read from `~/.config/codexcli/config.toml` or a project's
+/// `.codexcli.toml`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: Option<String>,
+    pub workdir: Option<String>,
+    /// Deprecated: `format = "plain"`
+    pub raw: Option<bool>,
+    pub format: Option<OutputFormat>,
+    pub context: Option<bool>,
+    pub context_budget: Option<usize>,
+    pub system_prompt: Option<String>,
+    pub context_files: Option<Vec<String>>,
+    pub backend: Option<Backend>,
+    pub ollama_host: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f64>,
+    pub model_options: Option<serde_json::Map<String, serde_json::Value>>,
+    pub allow_exec: Option<bool>,
+    pub auto_execute: Option<AutoExecute>,
+    pub timeout: Option<u64>,
+    pub shell: Option<String>,
+    pub db: Option<String>,
+    pub log: Option<bool>,
+    pub port: Option<u16>,
+    pub bind: Option<String>,
+    pub animation: Option<bool>,
+    pub minimal: Option<bool>,
+    pub line_numbers: Option<bool>,
+    pub show_thinking: Option<bool>,
+    pub pager: Option<bool>,
+    pub stats: Option<bool>,
+    pub banner_font: Option<String>,
+    pub spinner_style: Option<String>,
+    pub accent_color: Option<String>,
+    pub profiles: Option<BTreeMap<String, Config>>,
+}
+
+/// Final settings after merging CLI > env > file > hardcoded defaults
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    /// The `--profile` in effect
+    pub profile: Option<String>,
+    pub model: String,
+    pub workdir: Option<String>,
+    pub format: OutputFormat,
+    /// No banners, spinners or styling: every format but ansi, and --quiet
+    pub raw: bool,
+    pub context: bool,
+    pub context_budget: usize,
+    pub system_prompt: Option<String>,
+    pub context_files: Vec<String>,
+    /// None picks one at startup
+    pub backend: Option<Backend>,
+    /// Overrides OLLAMA_HOST for everything that talks to Ollama
+    pub ollama_host: Option<String>,
+    pub max_tokens: Option<u32>,
+    pub temperature: Option<f64>,
+    /// Extra Ollama options, the file's first so the command line's win
+    pub model_options: Vec<(String, serde_json::Value)>,
+    pub allow_exec: bool,
+    pub auto_execute: AutoExecute,
+    pub allow_dangerous: bool,
+    pub allow_unsafe_workdir: bool,
+    pub keep_temp: bool,
+    /// Install packages that Python and Node blocks are missing
+    pub autoinstall: bool,
+    pub yes: bool,
+    pub copy: bool,
+    pub capture: bool,
+    pub auto_fix: bool,
+    pub dry_run: bool,
+    pub reinstall_venv: bool,
+    pub no_banner: bool,
+    pub minimal: bool,
+    pub animation: bool,
+    pub line_numbers: bool,
+    pub show_thinking: bool,
+    pub pager: bool,
+    pub stats: bool,
+    pub banner_font: Option<String>,
+    pub spinner_style: Option<String>,
+    pub accent_color: Option<String>,
+    pub timeout: Option<Duration>,
+    /// Shell for `!` commands; None runs them directly
+    pub shell: Option<String>,
+    /// SQLite database for sql blocks; None keeps one in memory
+    pub db: Option<String>,
+    pub log: bool,
+    /// File a one-shot response is also written to
+    pub output_file: Option<String>,
+    /// Print nothing but errors (with `output_file`)
+    pub quiet: bool,
+    /// JSON lines file that code blocks are appended to
+    pub export_blocks: Option<String>,
+    pub port: u16,
+    pub bind: Option<String>,
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".config").join("codexcli"))
+}
+
+/// Load `config.toml` from `dir`, writing the commented template there if
+/// there isn't one yet
+fn load_config(dir: &Path) -> Result<Config, CodexError> {
+    let path = dir.join("config.toml");
+    let failed = |e: &dyn std::fmt::Display| CodexError::Config(format!("{}: {}", path.display(), e));
+
+    if !path.exists() {
+        fs::create_dir_all(dir).map_err(|e| failed(&e))?;
+        fs::write(&path, CONFIG_TEMPLATE).map_err(|e| failed(&e))?;
+        return Ok(Config::default());
+    }
+
+    debug!("loading config from {}", path.display());
+    let contents = fs::read_to_string(&path).map_err(|e| failed(&e))?;
+    toml::from_str(&contents).map_err(|e| failed(&e))
+}
+
+/// The nearest `.codexcli.toml` in `start` or one of its parents
+fn find_project_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG))
+        .find(|path| path.is_file())
+}
+
+/// Load the project config for `start`, if any. A relative `workdir` in it
+/// is taken relative to the file, so the setting works from any subdirectory.
+fn load_project_config(start: &Path) -> Result<Config, CodexError> {
+    let Some(path) = find_project_config(start) else {
+        return Ok(Config::default());
+    };
+    debug!("loading project config from {}", path.display());
+    let failed = |e: &dyn std::fmt::Display| CodexError::Config(format!("{}: {}", path.display(), e));
+    let contents = fs::read_to_string(&path).map_err(|e| failed(&e))?;
+    let mut config: Config = toml::from_str(&contents).map_err(|e| failed(&e))?;
+    if let (Some(workdir), Some(dir)) = (&config.workdir, path.parent()) {
+        config.workdir = Some(dir.join(workdir).to_string_lossy().into_owned());
+    }
+    Ok(config)
+}
+
+impl Config {
+    /// Clear the settings a project config may not set, returning their
+    /// names. A `.codexcli.toml` comes with whatever repository was cloned,
+    /// so it mustn't be able to turn on code execution, pick the program
+    /// that runs `!` commands, or send prompts, files or queries elsewhere.
+    fn drop_untrusted(&mut self) -> Vec<&'static str> {
+        let mut dropped: Vec<&'static str> = [
+            ("allow_exec", self.allow_exec.take().is_some()),
+            ("auto_execute", self.auto_execute.take().is_some()),
+            ("shell", self.shell.take().is_some()),
+            ("ollama_host", self.ollama_host.take().is_some()),
+            ("db", self.db.take().is_some()),
+            ("context_files", self.context_files.take().is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        for profile in self.profiles.iter_mut().flat_map(|profiles| profiles.values_mut()) {
+            for name in profile.drop_untrusted() {
+                if !dropped.contains(&name) {
+                    dropped.push(name);
+                }
+            }
+        }
+        dropped
+    }
+
+    /// Fill every unset field from `fallback`
+    fn or(self, fallback: Config) -> Config {
+        Config {
+            model: self.model.or(fallback.model),
+            workdir: self.workdir.or(fallback.workdir),
+            raw: self.raw.or(fallback.raw),
+            format: self.format.or(fallback.format),
+            context: self.context.or(fallback.context),
+            context_budget: self.context_budget.or(fallback.context_budget),
+            system_prompt: self.system_prompt.or(fallback.system_prompt),
+            context_files: self.context_files.or(fallback.context_files),
+            backend: self.backend.or(fallback.backend),
+            ollama_host: self.ollama_host.or(fallback.ollama_host),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            temperature: self.temperature.or(fallback.temperature),
+            model_options: self.model_options.or(fallback.model_options),
+            allow_exec: self.allow_exec.or(fallback.allow_exec),
+            auto_execute: self.auto_execute.or(fallback.auto_execute),
+            timeout: self.timeout.or(fallback.timeout),
+            shell: self.shell.or(fallback.shell),
+            db: self.db.or(fallback.db),
+            log: self.log.or(fallback.log),
+            port: self.port.or(fallback.port),
+            bind: self.bind.or(fallback.bind),
+            animation: self.animation.or(fallback.animation),
+            minimal: self.minimal.or(fallback.minimal),
+            line_numbers: self.line_numbers.or(fallback.line_numbers),
+            show_thinking: self.show_thinking.or(fallback.show_thinking),
+            pager: self.pager.or(fallback.pager),
+            stats: self.stats.or(fallback.stats),
+            banner_font: self.banner_font.or(fallback.banner_font),
+            spinner_style: self.spinner_style.or(fallback.spinner_style),
+            accent_color: self.accent_color.or(fallback.accent_color),
+            // A project's profiles add to the global ones, replacing any of
+            // the same name
+            profiles: match (self.profiles, fallback.profiles) {
+                (Some(mut profiles), Some(fallback)) => {
+                    for (name, profile) in fallback {
+                        profiles.entry(name).or_insert(profile);
+                    }
+                    Some(profiles)
+                }
+                (profiles, fallback) => profiles.or(fallback),
+            },
+        }
+    }
+}
+
+fn env_config() -> Config {
+    Config {
+        model: env::var("CODEXCLI_MODEL").ok(),
+        workdir: env::var("CODEXCLI_WORKDIR").ok(),
+        raw: env::var("CODEXCLI_RAW")
+            .ok()
+            .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes")),
+        context: None,
+        context_budget: env::var("CODEXCLI_CONTEXT_BUDGET")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        allow_exec: None,
+        timeout: env::var("CODEXCLI_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        log: None,
+        ..Config::default()
+    }
+}
+
+/// The user's shell: `$SHELL` on Unix, `%COMSPEC%` (cmd) on Windows
+fn default_shell() -> String {
+    let var = if cfg!(windows) { "COMSPEC" } else { "SHELL" };
+    env::var(var)
+        .ok()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "cmd".to_string() } else { "sh".to_string() })
+}
+
+/// Merge the layers in precedence order: CLI > env > file > hardcoded, where
+/// `file` is the project config laid over the global one
+pub fn merge_config(args: Args, env: Config, file: Config) -> ResolvedConfig {
+    // --raw, --json and the raw setting are older spellings of a format
+    let plain = |raw: Option<bool>| raw.filter(|raw| *raw).map(|_| OutputFormat::Plain);
+    let format = args
+        .format
+        .or(args.json.then_some(OutputFormat::Json))
+        .or(args.raw.then_some(OutputFormat::Plain))
+        .or(plain(env.raw))
+        .or(file.format)
+        .or(plain(file.raw))
+        .unwrap_or_default();
+
+    let minimal = args.minimal || file.minimal.unwrap_or(false);
+
+    ResolvedConfig {
+        profile: args.profile,
+        model: args
+            .model
+            .or(env.model)
+            .or(file.model)
+            .unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+        workdir: args.workdir.or(env.workdir).or(file.workdir),
+        format,
+        raw: format != OutputFormat::Ansi || args.quiet,
+        context: !args.no_context && env.context.or(file.context).unwrap_or(true),
+        context_budget: args
+            .context_budget
+            .or(env.context_budget)
+            .or(file.context_budget)
+            .unwrap_or(DEFAULT_CONTEXT_BUDGET),
+        system_prompt: args.system_prompt.or(file.system_prompt),
+        context_files: if args.context_files.is_empty() {
+            file.context_files.unwrap_or_default()
+        } else {
+            args.context_files
+        },
+        backend: args.backend.or(file.backend),
+        // OLLAMA_HOST itself is read where it's used, so the file setting
+        // only applies when it isn't set
+        ollama_host: args
+            .ollama_host
+            .or_else(|| file.ollama_host.filter(|_| env::var_os("OLLAMA_HOST").is_none())),
+        max_tokens: args.max_tokens.or(file.max_tokens.filter(|n| *n > 0)),
+        temperature: args.temperature.or(file.temperature.filter(|t| {
+            let valid = (0.0..=2.0).contains(t);
+            if !valid {
+                show_warning(&format!("Ignoring temperature = {} in config: must be between 0.0 and 2.0", t));
+            }
+            valid
+        })),
+        model_options: file
+            .model_options
+            .unwrap_or_default()
+            .into_iter()
+            .chain(args.model_option)
+            .collect(),
+        allow_exec: args.allow_exec || env.allow_exec.or(file.allow_exec).unwrap_or(false),
+        auto_execute: args.execute.or(file.auto_execute).unwrap_or_default(),
+        allow_dangerous: args.allow_dangerous,
+        allow_unsafe_workdir: args.allow_unsafe_workdir,
+        keep_temp: args.keep_temp,
+        autoinstall: !args.no_autoinstall,
+        yes: args.yes,
+        copy: args.copy,
+        capture: args.capture,
+        auto_fix: args.auto_fix,
+        dry_run: args.dry_run,
+        reinstall_venv: args.reinstall_venv,
+        no_banner: args.no_banner || minimal,
+        minimal,
+        animation: !args.no_animation && file.animation.unwrap_or(true),
+        line_numbers: args.line_numbers || file.line_numbers.unwrap_or(false),
+        show_thinking: args.show_thinking || file.show_thinking.unwrap_or(false),
+        pager: args.pager || file.pager.unwrap_or(false),
+        stats: args.stats || file.stats.unwrap_or(false),
+        banner_font: file.banner_font,
+        spinner_style: file.spinner_style,
+        accent_color: file.accent_color,
+        timeout: Some(
+            args.timeout
+                .or(env.timeout)
+                .or(file.timeout)
+                .unwrap_or(DEFAULT_TIMEOUT_SECS),
+        )
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs),
+        shell: (!args.no_shell).then(|| args.shell.or(file.shell).unwrap_or_else(default_shell)),
+        db: args.db.or(file.db),
+        output_file: args.output_file,
+        quiet: args.quiet,
+        export_blocks: args.export_blocks,
+        log: args.log || env.log.or(file.log).unwrap_or(false),
+        port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+        bind: args.bind.or(file.bind),
+    }
+}
+
+/// The project config laid over the global one
+fn file_config(warn: bool) -> Config {
+    let file = config_dir()
+        .ok_or_else(|| CodexError::Config("Could not determine home directory".to_string()))
+        .and_then(|dir| load_config(&dir))
+        .unwrap_or_else(|e| {
+            if warn {
+                show_warning(&format!("Ignoring config file: {}", e));
+            }
+            Config::default()
+        });
+    let cwd = env::current_dir().unwrap_or_default();
+    let mut project = load_project_config(&cwd).unwrap_or_else(|e| {
+        if warn {
+            show_warning(&format!("Ignoring project config: {}", e));
+        }
+        Config::default()
+    });
+    let dropped = project.drop_untrusted();
+    if warn && !dropped.is_empty() {
+        show_warning(&format!(
+            "Ignoring {} in {}: set them in ~/.config/codexcli/config.toml or on the command line",
+            dropped.join(", "),
+            PROJECT_CONFIG
+        ));
+    }
+    project.or(file)
+}
+
+/// Names of the `[profiles.<name>]` sections, sorted
+pub fn list_profiles() -> Vec<String> {
+    file_config(false).profiles.unwrap_or_default().into_keys().collect()
+}
+
+/// Resolve the settings, with the `--profile` (if any) laid over the config
+/// files. Fails if there's no such profile.
+pub fn resolve_config(args: Args) -> Result<ResolvedConfig, CodexError> {
+    let mut file = file_config(true);
+    if let Some(name) = &args.profile {
+        let profile = file.profiles.as_mut().and_then(|profiles| profiles.remove(name)).ok_or_else(|| {
+            let names = list_profiles();
+            CodexError::Config(if names.is_empty() {
+                format!("No profile '{}': the config has no [profiles.<name>] sections", name)
+            } else {
+                format!("No profile '{}'. Available: {}", name, names.join(", "))
+            })
+        })?;
+        file = profile.or(file);
+    }
+    Ok(merge_config(args, env_config(), file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::parse_from(std::iter::once("codexcli").chain(flags.iter().copied()))
+    }
+
+    #[test]
+    fn first_load_writes_the_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = load_config(dir.path()).unwrap();
+        assert!(config.model.is_none());
+        assert_eq!(fs::read_to_string(dir.path().join("config.toml")).unwrap(), CONFIG_TEMPLATE);
+        // Everything in the template is commented out
+        assert!(load_config(dir.path()).unwrap().model.is_none());
+    }
+
+    #[test]
+    fn loads_settings_from_the_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("config.toml"), "model = \"mistral\"\ntimeout = 5\n").unwrap();
+        let config = load_config(dir.path()).unwrap();
+        assert_eq!(config.model.as_deref(), Some("mistral"));
+        assert_eq!(config.timeout, Some(5));
+    }
+
+    #[test]
+    fn bad_config_names_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("config.toml"), "colour = \"red\"\n").unwrap();
+        let err = load_config(dir.path()).unwrap_err();
+        assert!(matches!(&err, CodexError::Config(message) if message.contains("config.toml")), "{}", err);
+    }
+
+    fn layer(model: &str, timeout: u64) -> Config {
+        Config {
+            model: Some(model.to_string()),
+            timeout: Some(timeout),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn project_config_cannot_enable_execution() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("src");
+        fs::create_dir(&nested).unwrap();
+        fs::write(
+            root.path().join(PROJECT_CONFIG),
+            r#"
+model = "codellama"
+allow_exec = true
+auto_execute = "always"
+shell = "/tmp/evil"
+ollama_host = "http://elsewhere:11434"
+db = "/etc/data.db"
+context_files = ["~/.ssh/*"]
+
+[profiles.coding]
+allow_exec = true
+temperature = 0.2
+"#,
+        )
+        .unwrap();
+
+        let mut project = load_project_config(&nested).unwrap();
+        assert_eq!(
+            project.drop_untrusted(),
+            ["allow_exec", "auto_execute", "shell", "ollama_host", "db", "context_files"]
+        );
+        assert_eq!(project.model.as_deref(), Some("codellama"));
+        let coding = &project.profiles.as_ref().unwrap()["coding"];
+        assert!(coding.allow_exec.is_none());
+        assert_eq!(coding.temperature, Some(0.2));
+
+        let config = merge_config(args(&[]), Config::default(), project.or(Config::default()));
+        assert!(!config.allow_exec);
+        assert_eq!(config.auto_execute, AutoExecute::Ask);
+        assert!(config.context_files.is_empty());
+        assert!(config.db.is_none());
+        assert_ne!(config.shell.as_deref(), Some("/tmp/evil"));
+    }
+
+    #[test]
+    fn project_workdir_is_relative_to_the_file() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join(PROJECT_CONFIG), "workdir = \"scratch\"\n").unwrap();
+        let project = load_project_config(root.path()).unwrap();
+        assert_eq!(project.workdir, Some(root.path().join("scratch").to_string_lossy().into_owned()));
+    }
+
+    #[test]
+    fn defaults_apply_when_nothing_is_set() {
+        let config = merge_config(args(&[]), Config::default(), Config::default());
+        assert_eq!(config.model, DEFAULT_MODEL);
+        assert_eq!(config.timeout, Some(Duration::from_secs(DEFAULT_TIMEOUT_SECS)));
+        assert_eq!(config.port, DEFAULT_PORT);
+        assert!(!config.allow_exec);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = merge_config(args(&[]), Config::default(), layer("file", 1));
+        assert_eq!(config.model, "file");
+        assert_eq!(config.timeout, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let config = merge_config(args(&[]), layer("env", 2), layer("file", 1));
+        assert_eq!(config.model, "env");
+        assert_eq!(config.timeout, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn cli_overrides_env_and_file() {
+        let config = merge_config(args(&["--model", "cli", "--timeout", "3"]), layer("env", 2), layer("file", 1));
+        assert_eq!(config.model, "cli");
+        assert_eq!(config.timeout, Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn zero_timeout_disables_it() {
+        let config = merge_config(args(&["--timeout", "0"]), Config::default(), layer("file", 1));
+        assert_eq!(config.timeout, None);
+    }
+}
@@ -0,0 +1,68 @@
+use std::{io, time::Duration};
+use thiserror::Error;
+
+/// Everything that can go wrong running prompts and code blocks. The
+/// variants let callers react to the kind of failure (e.g. only offer an
+/// automatic fix when the code itself was at fault); `Display` is the
+/// message shown to the user.
+#[derive(Debug, Error)]
+pub enum CodexError {
+    /// A program needed to run a block (interpreter, compiler) is missing
+    #[error("{name} is not installed or not on PATH ({hint})")]
+    InterpreterNotFound { name: String, hint: String },
+
+    #[error("Compilation failed ({compiler}):\n{output}")]
+    CompileFailed { compiler: String, output: String },
+
+    /// The program ran and failed
+    #[error("{0}")]
+    Runtime(String),
+
+    #[error("Execution timed out after {} s", .0.as_secs())]
+    Timeout(Duration),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// Ollama couldn't be reached or refused the request
+    #[error("{0}")]
+    ModelUnavailable(String),
+
+    /// The model hasn't been pulled
+    #[error("Model '{0}' is not installed. Pull it with `ollama pull {0}`")]
+    ModelNotFound(String),
+
+    /// A config file couldn't be read or has invalid settings
+    #[error("{0}")]
+    Config(String),
+
+    /// Stopped with Ctrl-C
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl CodexError {
+    /// Whether the failure lies in the code itself, so asking the model for a
+    /// corrected version could help
+    pub fn is_code_error(&self) -> bool {
+        matches!(
+            self,
+            CodexError::CompileFailed { .. } | CodexError::Runtime(_) | CodexError::Timeout(_)
+        )
+    }
+}
+
+impl From<String> for CodexError {
+    fn from(message: String) -> Self {
+        CodexError::Other(message)
+    }
+}
+
+impl From<&str> for CodexError {
+    fn from(message: &str) -> Self {
+        CodexError::Other(message.to_string())
+    }
+}
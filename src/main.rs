@@ -1,754 +1,6144 @@
 #![allow(dead_code)]  // silence unused‐function warnings
 
+mod config;
+mod error;
+mod templates;
+mod transcript;
+
+use chrono::{DateTime, Local};
 use clap::Parser;
 use figlet_rs::FIGfont;
-use owo_colors::OwoColorize;
+use serde::Serialize;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::{
+    cell::Cell,
+    collections::HashMap,
     fs::{self, File},
-    io::{self, Write},
-    path::Path,
-    process::{Command, Stdio},
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output, Stdio},
     env,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
     thread,
 };
-use console::style;
+use console::{style, Style, Term};
 use duct::cmd;
+use log::{debug, trace};
+use rusqlite::{fallible_iterator::FallibleIterator, types::ValueRef, Batch, Connection};
+use similar::{ChangeTag, TextDiff};
+use wait_timeout::ChildExt;
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Context, Editor, Helper,
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{Theme, ThemeSet},
+    parsing::SyntaxSet,
+    util::as_24_bit_terminal_escaped,
+};
+use config::{list_profiles, resolve_config, AutoExecute, Backend, OutputFormat, ResolvedConfig};
+use error::CodexError;
+use templates::{expand_template, list_templates};
+use transcript::{append_transcript, export_blocks, replay_transcript, BlockRecord};
 
 /// CodexCLI - AI at your terminal's service
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(name = "codexcli", version = "1.0", author = "Anvin", about = "Ask AI anything")]
 struct Args {
-    /// Disable fancy UI and animations
+    /// Use the settings in the config's [profiles.<NAME>] section, on top of
+    /// the rest of the config
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// How responses are printed: ansi (default), plain, markdown (no ANSI
+    /// codes, for piping into a renderer) or json
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    format: Option<OutputFormat>,
+
+    /// Deprecated: same as --format plain
     #[arg(long)]
     raw: bool,
     
     /// Set the working directory for code execution
     #[arg(long)]
     workdir: Option<String>,
+
+    /// Ollama model to use for responses [default: llama3.2]
+    #[arg(long)]
+    model: Option<String>,
+
+    /// Send each prompt on its own without earlier conversation turns
+    #[arg(long)]
+    no_context: bool,
+
+    /// Maximum characters of conversation history to send [default: 8000]
+    #[arg(long, value_name = "CHARS")]
+    context_budget: Option<usize>,
+
+    /// Allow executing code blocks from AI responses
+    #[arg(long)]
+    allow_exec: bool,
+
+    /// Whether to run code blocks without asking [default: ask]; `always`
+    /// still needs --allow-exec
+    #[arg(long, value_enum, value_name = "WHEN")]
+    execute: Option<AutoExecute>,
+
+    /// Also allow shell blocks that look destructive (e.g. rm -rf /)
+    #[arg(long, requires = "allow_exec")]
+    allow_dangerous: bool,
+
+    /// Keep the source files and build output of code blocks instead of
+    /// deleting them, and print where they are
+    #[arg(long, visible_alias = "no-cleanup")]
+    keep_temp: bool,
+
+    /// Report missing Python and Node packages instead of installing them
+    #[arg(long)]
+    no_autoinstall: bool,
+
+    /// Allow the filesystem root or your home directory as the workdir
+    #[arg(long)]
+    allow_unsafe_workdir: bool,
+
+    /// Pipe code block output instead of handing programs the terminal, so
+    /// it ends up in the result (programs can't read input in this mode)
+    #[arg(long)]
+    capture: bool,
+
+    /// When a code block fails, ask the model for a corrected version and
+    /// offer to run it (up to 3 attempts)
+    #[arg(long, requires = "allow_exec")]
+    auto_fix: bool,
+
+    /// Delete and rebuild the Python virtual environment in the workdir
+    #[arg(long)]
+    reinstall_venv: bool,
+
+    /// Keep colors and formatting but draw spinners without animating them
+    #[arg(long)]
+    no_animation: bool,
+
+    /// Skip the ASCII-art banner at startup
+    #[arg(long)]
+    no_banner: bool,
+
+    /// Keep the colors and highlighting but drop the banner, dividers and
+    /// emoji
+    #[arg(long)]
+    minimal: bool,
+
+    /// Default port for `start-server`; the next free one is used if taken
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Address for `start-server` to listen on (e.g. 127.0.0.1); all
+    /// interfaces by default
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<String>,
+
+    /// SQLite database file that sql blocks run against; an in-memory one
+    /// lasting the session by default
+    #[arg(long, value_name = "PATH")]
+    db: Option<String>,
+
+    /// Number the lines of code blocks in responses
+    #[arg(long)]
+    line_numbers: bool,
+
+    /// Show the <think> reasoning of reasoning models, dimmed, instead of
+    /// hiding it
+    #[arg(long)]
+    show_thinking: bool,
+
+    /// Show what code blocks and `!` commands would run instead of running them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Log what's happening under the hood to stderr (-vv for more)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Stop generating after this many tokens [default: the model's own]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_tokens: Option<u32>,
+
+    /// Sampling temperature from 0.0 (focused) to 2.0 (creative)
+    /// [default: the model's own, usually 0.8]
+    #[arg(long, value_name = "T", value_parser = parse_temperature)]
+    temperature: Option<f64>,
+
+    /// Set any other Ollama option, e.g. --model-option seed=42 or
+    /// --model-option top_p=0.9; repeat the flag for more
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_model_option)]
+    model_option: Vec<(String, serde_json::Value)>,
+
+    /// Copy each response to the clipboard
+    #[arg(long)]
+    copy: bool,
+
+    /// Send the files matching this glob with every prompt; quote it (e.g.
+    /// 'src/**/*.rs') and repeat the flag for more
+    #[arg(long, value_name = "GLOB")]
+    context_files: Vec<String>,
+
+    /// Instructions sent ahead of every prompt, e.g. "You are a terse Rust expert"
+    #[arg(long, value_name = "TEXT")]
+    system_prompt: Option<String>,
+
+    /// Fill the named template from ~/.config/codexcli/templates/ with the
+    /// prompt and send that instead
+    #[arg(long, value_name = "NAME")]
+    template: Option<String>,
+
+    /// Read the system prompt from a file
+    #[arg(long, value_name = "PATH", conflicts_with = "system_prompt")]
+    system_prompt_file: Option<String>,
+
+    /// Shell that runs `!` commands [default: $SHELL, or cmd on Windows]
+    #[arg(long, value_name = "NAME")]
+    shell: Option<String>,
+
+    /// Run `!` commands directly instead of through a shell (no pipes,
+    /// globs or variables)
+    #[arg(long, conflicts_with = "shell")]
+    no_shell: bool,
+
+    /// Run mutating `!` commands (rm, mv, git push, ...) without asking
+    #[arg(short = 'y', long)]
+    yes: bool,
+
+    /// Kill code blocks that run longer than this; 0 disables [default: 30]
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Same as --format json: one JSON object per prompt
+    #[arg(long)]
+    json: bool,
+
+    /// List the locally installed Ollama models and exit
+    #[arg(long)]
+    list_models: bool,
+
+    /// How to reach Ollama: its HTTP API, or the ollama CLI. By default the
+    /// API when the server answers, else the CLI.
+    #[arg(long, value_enum, value_name = "BACKEND")]
+    backend: Option<Backend>,
+
+    /// Ollama server to use, e.g. http://gpu-box:11434 (sets OLLAMA_HOST for
+    /// the ollama CLI). Code blocks still run on this machine.
+    #[arg(long, value_name = "URL")]
+    ollama_host: Option<String>,
+
+    /// Append each prompt and response to ~/.config/codexcli/sessions/<date>.md
+    #[arg(long)]
+    log: bool,
+
+    /// Show responses taller than the terminal in $PAGER (less -R by default)
+    #[arg(long)]
+    pager: bool,
+
+    /// After each response, show how long it took and how many tokens per
+    /// second the model generated
+    #[arg(long)]
+    stats: bool,
+
+    /// Append a JSON line for every code block in a response (its prompt,
+    /// language, code and whether and how it ran) to this file
+    #[arg(long, value_name = "FILE")]
+    export_blocks: Option<String>,
+
+    /// Answer the prompt with each of these models (comma-separated) and
+    /// compare their timing
+    #[arg(long, value_name = "MODELS", value_delimiter = ',')]
+    compare: Vec<String>,
+
+    /// Also write the response to this file, creating its directories. The
+    /// --format decides what's written: markdown for ansi and markdown, the
+    /// response verbatim for plain, the JSON object for json
+    #[arg(long, value_name = "PATH")]
+    output_file: Option<String>,
+
+    /// With --output-file, print only errors to the terminal
+    #[arg(short, long, requires = "output_file")]
+    quiet: bool,
+
+    /// Print a saved session transcript and exit
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
+
+    /// Answer the contents of this file, like a prompt given as an argument
+    #[arg(long, value_name = "PATH", conflicts_with = "prompt")]
+    prompt_file: Option<String>,
+
+    /// Answer this prompt and exit instead of starting the interactive loop
+    prompt: Option<String>,
+}
+
+/// Diagnostics go to stderr: `-v` shows debug messages and `-vv` traces
+/// (prompts, sources, tool output). Without either, `RUST_LOG` decides, and
+/// nothing is logged by default.
+fn init_logging(verbosity: u8) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("off"));
+    match verbosity {
+        0 => (),
+        1 => {
+            builder.filter_module("codex_cli", log::LevelFilter::Debug);
+        }
+        _ => {
+            builder.filter_module("codex_cli", log::LevelFilter::Trace);
+        }
+    }
+    builder.format_timestamp(None).init();
+}
+
+/// Decide once whether to emit ANSI colors. They are off when `NO_COLOR` is
+/// set (https://no-color.org) or the output isn't a terminal, unless
+/// `CLICOLOR_FORCE` asks for them. Every styled print goes through
+/// `console`, so this covers the whole UI independently of `--raw`.
+fn init_colors() {
+    let forced = env::var("CLICOLOR_FORCE").is_ok_and(|v| v != "0");
+    let no_color = env::var("NO_COLOR").is_ok_and(|v| !v.is_empty());
+    let clicolor_off = env::var("CLICOLOR").is_ok_and(|v| v == "0");
+    let allowed = forced || (!no_color && !clicolor_off);
+
+    console::set_colors_enabled(allowed && (forced || io::stdout().is_terminal()));
+    console::set_colors_enabled_stderr(allowed && (forced || io::stderr().is_terminal()));
+}
+
+/// Spinner presets selectable with `spinner_style`; the last frame is shown
+/// when the spinner finishes
+const SPINNER_STYLES: &[(&str, &str)] = &[
+    ("dots", "⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏"),
+    ("line", "-\\|/ "),
+    ("arc", "◜◠◝◞◡◟ "),
+    ("circle", "◐◓◑◒ "),
+    ("arrows", "←↖↑↗→↘↓↙ "),
+    ("bounce", "⠁⠂⠄⠂ "),
+];
+
+/// Colors accepted for `accent_color`, by their console/indicatif name
+const ACCENT_COLORS: &[&str] = &["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// Spinner frames, accent color, whether to animate and whether to number
+/// code lines, chosen once at startup from the config
+struct Appearance {
+    spinner_chars: &'static str,
+    accent: &'static str,
+    animate: bool,
+    line_numbers: bool,
+    show_thinking: bool,
+    /// `--minimal`: no dividers or emoji
+    minimal: bool,
+}
+
+static APPEARANCE: OnceLock<Appearance> = OnceLock::new();
+
+fn appearance() -> &'static Appearance {
+    APPEARANCE.get_or_init(|| Appearance {
+        spinner_chars: SPINNER_STYLES[0].1,
+        accent: "green",
+        animate: true,
+        line_numbers: false,
+        show_thinking: false,
+        minimal: false,
+    })
+}
+
+/// Apply `spinner_style` and `accent_color`, warning about unknown values
+/// and keeping the defaults for them
+fn init_appearance(config: &ResolvedConfig) {
+    let spinner_chars = match config.spinner_style.as_deref() {
+        None => SPINNER_STYLES[0].1,
+        Some(name) => SPINNER_STYLES
+            .iter()
+            .find(|(style, _)| style.eq_ignore_ascii_case(name))
+            .map(|(_, chars)| *chars)
+            .unwrap_or_else(|| {
+                let names: Vec<&str> = SPINNER_STYLES.iter().map(|(style, _)| *style).collect();
+                show_warning(&format!("Unknown spinner_style '{}', expected one of: {}", name, names.join(", ")));
+                SPINNER_STYLES[0].1
+            }),
+    };
+    let accent = match config.accent_color.as_deref() {
+        None => "green",
+        Some(name) => ACCENT_COLORS
+            .iter()
+            .find(|color| color.eq_ignore_ascii_case(name))
+            .copied()
+            .unwrap_or_else(|| {
+                show_warning(&format!("Unknown accent_color '{}', expected one of: {}", name, ACCENT_COLORS.join(", ")));
+                "green"
+            }),
+    };
+    let _ = APPEARANCE.set(Appearance {
+        spinner_chars,
+        accent,
+        animate: config.animation,
+        line_numbers: config.line_numbers,
+        show_thinking: config.show_thinking,
+        minimal: config.minimal,
+    });
+}
+
+fn spinner_style() -> ProgressStyle {
+    let Appearance { spinner_chars, accent, .. } = appearance();
+    ProgressStyle::with_template(&format!("{{spinner:.{}}} {{msg}}", accent))
+        .unwrap()
+        .tick_chars(spinner_chars)
+}
+
+/// A percentage bar in the accent color, for downloads
+fn progress_style() -> ProgressStyle {
+    let Appearance { spinner_chars, accent, .. } = appearance();
+    ProgressStyle::with_template(&format!("{{spinner:.{0}}} [{{bar:30.{0}/dim}}] {{pos:>3}}% {{msg}}", accent))
+        .unwrap()
+        .tick_chars(spinner_chars)
+        .progress_chars("█▉▊▋▌▍▎▏ ")
 }
 
-fn print_banner() {
-    let standard_font = FIGfont::standard()
-        .unwrap_or_else(|_| FIGfont::from_content("").unwrap());
-    let figure = standard_font.convert("CodexCLI").unwrap();
-    println!("\n{}", figure.to_string().bright_blue().bold());
+/// The FIGlet font at `path`, or the built-in standard font if it's unset or
+/// can't be loaded
+fn banner_font(path: Option<&str>) -> FIGfont {
+    if let Some(path) = path {
+        match FIGfont::from_file(path) {
+            Ok(font) => return font,
+            Err(_) if !Path::new(path).is_file() => {
+                show_warning(&format!("banner_font '{}' not found. Using the standard font.", path))
+            }
+            Err(e) => show_warning(&format!("Could not load banner_font '{}': {}. Using the standard font.", path, e)),
+        }
+    }
+    FIGfont::standard().unwrap_or_else(|_| FIGfont::from_content("").unwrap())
+}
+
+fn print_banner(font: Option<&str>) {
+    let font = banner_font(font);
+    let figure = font.convert("CodexCLI").unwrap();
+    let accent = Style::from_dotted_str(appearance().accent).bright().bold();
+    println!("\n{}", accent.apply_to(figure.to_string()));
     println!("{}", style("AI at your terminal's service").dim());
-    println!("{}", style("─────────────────────────────").dim());
+    println!("{}", style(DIVIDER).dim());
     println!();
 }
 
-fn show_animated_message(message: &str, duration: Duration) {
+/// A spinner that runs until the caller finishes it, so it lasts exactly as
+/// long as the work behind it. With animations off it is drawn once and
+/// stays still.
+fn show_spinner(message: &str) -> ProgressBar {
     let spinner = ProgressBar::new_spinner();
+    spinner.set_style(spinner_style());
     spinner.set_message(message.to_string());
-    spinner.enable_steady_tick(Duration::from_millis(80));
-    spinner.set_style(
-        ProgressStyle::with_template("{spinner:.green} {msg}")
-            .unwrap()
-            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏"),
-    );
-    thread::sleep(duration);
-    spinner.finish_and_clear();
+    if appearance().animate {
+        spinner.enable_steady_tick(Duration::from_millis(80));
+    } else {
+        spinner.tick();
+    }
+    spinner
 }
 
-fn show_spinner() -> ProgressBar {
+/// A spinner that also counts the seconds since it started, for waits of
+/// unknown length. A still spinner can't update, so it shows no count.
+fn show_timed_spinner(message: &str) -> ProgressBar {
+    let Appearance { spinner_chars, accent, animate, .. } = appearance();
+    if !animate {
+        return show_spinner(message);
+    }
     let spinner = ProgressBar::new_spinner();
-    spinner.set_message("Thinking...".to_string());
-    spinner.enable_steady_tick(Duration::from_millis(80));
     spinner.set_style(
-        ProgressStyle::with_template("{spinner:.green} {msg}")
+        ProgressStyle::with_template(&format!("{{spinner:.{}}} {{msg}} ({{elapsed}})", accent))
             .unwrap()
-            .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏"),
+            .tick_chars(spinner_chars),
     );
+    spinner.set_message(message.to_string());
+    spinner.enable_steady_tick(Duration::from_millis(80));
     spinner
 }
 
+const DIVIDER: &str = "─────────────────────────────";
+
+/// `emoji` and a space to start a label with, or nothing with `--minimal`
+fn icon(emoji: &str) -> String {
+    if appearance().minimal {
+        String::new()
+    } else {
+        format!("{} ", emoji)
+    }
+}
+
+/// The dim rule between sections, left out with `--minimal`
+fn print_divider() {
+    if !appearance().minimal {
+        println!("{}", style(DIVIDER).dim());
+    }
+}
+
+/// A section title on a line of its own, then the divider
+fn print_heading<T: std::fmt::Display>(title: T) {
+    println!("\n{}", title);
+    print_divider();
+}
+
 fn show_error_recovery(message: &str) {
-    println!("\n{} {}", style("🔄 Attempting to recover:").bold().yellow(), style(message).white());
-    show_animated_message("Recovering...", Duration::from_secs(1));
+    println!("\n{} {}", style(format!("{}Attempting to recover:", icon("🔄"))).bold().yellow(), style(message).white());
 }
 
 fn show_success(message: &str) {
-    println!("\n{} {}", style("✅ Success:").bold().green(), style(message).white());
+    println!("\n{} {}", style(format!("{}Success:", icon("✅"))).bold().green(), style(message).white());
 }
 
 fn show_warning(message: &str) {
-    println!("\n{} {}", style("⚠️ Warning:").bold().yellow(), style(message).white());
+    println!("\n{} {}", style(format!("{}Warning:", icon("⚠️"))).bold().yellow(), style(message).white());
 }
 
 fn show_error(message: &str) {
-    println!("\n{} {}", style("❌ Error:").bold().red(), style(message).red());
+    println!("\n{} {}", style(format!("{}Error:", icon("❌"))).bold().red(), style(message).red());
 }
 
-fn format_response(response: &str) -> String {
-    let mut formatted = String::new();
-    for line in response.lines() {
-        if line.trim().is_empty() {
-            formatted.push_str("\n");
-        } else if line.trim().starts_with("```") {
-            formatted.push_str(&format!("{}\n", style(line).cyan()));
-        } else if line.trim().starts_with('#') {
-            formatted.push_str(&format!("{}\n", style(line).yellow().bold()));
-        } else if line.trim().starts_with('-') {
-            formatted.push_str(&format!("{}\n", style(line).green()));
+/// Style `line`, rendering `inline code` spans in bright cyan and the rest
+/// with `base`. An unmatched backtick is left as plain text.
+fn style_inline_code(line: &str, base: &Style) -> String {
+    let segments: Vec<&str> = line.split('`').collect();
+    let closed = segments.len() - (segments.len() + 1) % 2;
+    let mut out = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        if i % 2 == 1 && i < closed {
+            out.push_str(&Style::new().cyan().bright().apply_to(format!("`{}`", segment)).to_string());
+        } else if i % 2 == 1 {
+            out.push_str(&base.apply_to(format!("`{}", segment)).to_string());
         } else {
-            formatted.push_str(&format!("{}\n", style(line).white()));
+            out.push_str(&base.apply_to(segment).to_string());
         }
     }
-    formatted
+    out
+}
+
+// Loading syntect's bundled definitions takes a noticeable moment, so do
+// it once on first use rather than per response
+static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+fn syntax_set() -> &'static SyntaxSet {
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
 }
 
-fn get_user_input() -> String {
-    print!("{} ", style(">").bold().cyan());
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).unwrap();
-    input.trim_end().to_string()
+fn theme() -> &'static Theme {
+    THEME.get_or_init(|| {
+        let mut themes = ThemeSet::load_defaults();
+        themes.themes.remove("base16-ocean.dark").unwrap_or_default()
+    })
 }
 
-fn execute_command(command: &str) -> Result<String, String> {
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err("Empty command".to_string());
+/// Highlighter for a fence's language, or None if syntect doesn't know it
+fn highlighter_for(lang: &str) -> Option<HighlightLines<'static>> {
+    if lang.is_empty() || !console::colors_enabled() {
+        return None;
     }
+    let syntax = syntax_set().find_syntax_by_token(lang)?;
+    Some(HighlightLines::new(syntax, theme()))
+}
 
-    let output = Command::new(parts[0])
-        .args(&parts[1..])
-        .output()
-        .map_err(|e| e.to_string())?;
+/// Formats a response one line at a time, remembering whether it is inside
+/// a fenced code block so streamed output is styled the same as whole text
+#[derive(Default)]
+struct ResponseFormatter {
+    in_block: bool,
+    /// Lines seen so far in the current code block
+    block_line: usize,
+    highlighter: Option<HighlightLines<'static>>,
+    /// Prose is wrapped to this many columns; code is never wrapped
+    width: Option<usize>,
+    /// Rows of a markdown table, held until the table ends so its columns
+    /// can be lined up
+    table: Vec<String>,
+}
 
-    if output.status.success() {
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
-    } else {
-        Err(String::from_utf8_lossy(&output.stderr).to_string())
-    }
+/// Cells of a markdown table row, without the outer pipes
+fn table_cells(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    row.split('|').map(|cell| cell.trim().to_string()).collect()
 }
 
-fn extract_code_blocks(response: &str) -> Vec<(String, String)> {
-    let mut blocks = Vec::new();
-    let mut current = String::new();
-    let mut in_block = false;
-    let mut lang = String::new();
+/// The `---|:---:` row under a table header
+fn is_table_separator(line: &str) -> bool {
+    line.contains('|')
+        && table_cells(line)
+            .iter()
+            .all(|cell| cell.contains('-') && cell.chars().all(|c| matches!(c, '-' | ':')))
+}
 
-    for line in response.lines() {
-        if line.trim().starts_with("```") {
-            if in_block {
-                blocks.push((lang.clone(), current.clone()));
-                current.clear();
-                lang.clear();
-                in_block = false;
-            } else {
-                in_block = true;
-                lang = line.trim().trim_start_matches("```").to_string();
-            }
-        } else if in_block {
-            current.push_str(line);
-            current.push('\n');
-        }
+/// Column alignment from a separator cell: `:--`, `:-:` or `--:`
+fn cell_alignment(separator: &str) -> console::Alignment {
+    match (separator.starts_with(':'), separator.ends_with(':')) {
+        (true, true) => console::Alignment::Center,
+        (false, true) => console::Alignment::Right,
+        _ => console::Alignment::Left,
     }
+}
 
-    blocks
+/// Indent for the continuation lines of a wrapped line, so list items stay
+/// lined up under their text
+fn hanging_indent(line: &str) -> String {
+    let rest = line.trim_start();
+    let indent = line.len() - rest.len();
+    let digits = rest.chars().take_while(char::is_ascii_digit).count();
+    let marker = if rest.starts_with(['-', '*', '+']) && rest[1..].starts_with(' ') {
+        2
+    } else if digits > 0 && rest[digits..].starts_with(". ") {
+        digits + 2
+    } else {
+        0
+    };
+    " ".repeat(indent + marker)
 }
 
-fn setup_python_environment() -> Result<(), String> {
-    show_animated_message("Setting up Python environment...", Duration::from_secs(1));
-    
-    if !Path::new("venv").exists() {
-        let result = Command::new("python")
-            .args(&["-m", "venv", "venv"])
-            .output();
+impl ResponseFormatter {
+    /// A formatter that wraps prose to the terminal, when output is one
+    fn for_terminal() -> Self {
+        ResponseFormatter {
+            width: Term::stdout().size_checked().map(|(_, cols)| cols as usize),
+            ..Default::default()
+        }
+    }
 
-        match result {
-            Ok(_output) => (),
-            Err(_) => {
-                show_error_recovery("Python not found, attempting to install...");
-                // Try to install Python
-                if cfg!(windows) {
-                    Command::new("winget")
-                        .args(&["install", "Python.Python"])
-                        .output()
-                        .map_err(|e| e.to_string())?;
-                } else {
-                    Command::new("sudo")
-                        .args(&["apt-get", "install", "python3"])
-                        .output()
-                        .map_err(|e| e.to_string())?;
-                }
-                // Retry venv creation
-                Command::new("python")
-                    .args(&["-m", "venv", "venv"])
-                    .output()
-                    .map_err(|e| e.to_string())?;
+    fn wrap(&self, line: &str) -> Vec<String> {
+        match self.width {
+            Some(width) if console::measure_text_width(line) > width => {
+                let indent = hanging_indent(line);
+                textwrap::wrap(line, textwrap::Options::new(width).subsequent_indent(&indent))
+                    .into_iter()
+                    .map(|part| part.into_owned())
+                    .collect()
             }
+            _ => vec![line.to_string()],
         }
     }
 
-    let python_path = if cfg!(windows) {
-        "venv\\Scripts\\python.exe"
-    } else {
-        "venv/bin/python"
-    };
+    /// Wrap a prose line and style each resulting line
+    fn format_prose(&self, line: &str, base: &Style) -> String {
+        self.wrap(line)
+            .iter()
+            .map(|part| style_inline_code(part, base))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
 
-    // Install common packages with retry logic
-    let packages = ["pip", "setuptools", "wheel"];
-    for package in packages.iter() {
-        let mut attempts = 0;
-        while attempts < 3 {
-            let result = Command::new(python_path)
-                .args(&["-m", "pip", "install", "--upgrade", package])
-                .output();
+    /// Format one line of a response. Table rows are held back until the
+    /// table ends, so None means nothing is ready to print yet; `finish`
+    /// flushes whatever is still held at the end of the response.
+    fn format_line(&mut self, line: &str) -> Option<String> {
+        if self.in_block {
+            return Some(self.format_single(line));
+        }
 
-            match result {
-                Ok(_output) if _output.status.success() => break,
-                Ok(_output) => {
-                    show_warning(&format!("Failed to install {}, retrying...", package));
-                    attempts += 1;
-                    if attempts == 3 {
-                        return Err(format!("Failed to install {} after 3 attempts", package));
-                    }
-                    thread::sleep(Duration::from_secs(1));
-                }
-                Err(e) => return Err(e.to_string()),
+        let mut out = Vec::new();
+        match self.table.len() {
+            0 => {}
+            1 if is_table_separator(line) => {
+                self.table.push(line.to_string());
+                return None;
             }
+            // A lone line with a pipe in it was prose after all
+            1 => {
+                let first = self.table.remove(0);
+                out.push(self.format_single(&first));
+            }
+            _ if line.contains('|') => {
+                self.table.push(line.to_string());
+                return None;
+            }
+            _ => out.push(self.render_table()),
+        }
+
+        if line.contains('|') && !line.trim_start().starts_with("```") {
+            self.table.push(line.to_string());
+        } else {
+            out.push(self.format_single(line));
         }
+        (!out.is_empty()).then(|| out.join("\n"))
     }
 
-    show_success("Python environment setup complete");
-    Ok(())
-}
+    /// Flush a table still held when the response ends
+    fn finish(&mut self) -> Option<String> {
+        match self.table.len() {
+            0 => None,
+            1 => {
+                let first = self.table.remove(0);
+                Some(self.format_single(&first))
+            }
+            _ => Some(self.render_table()),
+        }
+    }
 
-fn install_python_package(package: &str) -> Result<(), String> {
-    println!("{} {}", style("Installing Python package:").bold().yellow(), style(package).white());
-    let python_path = if cfg!(windows) {
-        "venv\\Scripts\\python.exe"
-    } else {
-        "venv/bin/python"
-    };
-    
-    Command::new(python_path)
-        .args(&["-m", "pip", "install", package])
-        .output()
-        .map_err(|e| e.to_string())?;
-    Ok(())
-}
+    /// Draw the held table rows as a box with aligned columns
+    fn render_table(&mut self) -> String {
+        let rows: Vec<String> = self.table.drain(..).collect();
+        let alignments: Vec<console::Alignment> = table_cells(&rows[1]).iter().map(|c| cell_alignment(c)).collect();
+        let mut cells: Vec<Vec<String>> = rows
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 1)
+            .map(|(_, row)| table_cells(row))
+            .collect();
+        let columns = cells.iter().map(Vec::len).max().unwrap_or(0);
+        for row in &mut cells {
+            row.resize(columns, String::new());
+        }
+        let widths: Vec<usize> = (0..columns)
+            .map(|c| cells.iter().map(|row| console::measure_text_width(&row[c])).max().unwrap_or(0))
+            .collect();
 
-fn setup_node_environment() -> Result<(), String> {
-    // Create package.json if it doesn't exist
-    if !Path::new("package.json").exists() {
-        println!("{}", style("Setting up Node.js environment...").bold().yellow());
-        Command::new("npm")
-            .args(&["init", "-y"])
-            .output()
-            .map_err(|e| e.to_string())?;
-    }
-    Ok(())
-}
+        let border = |left: &str, middle: &str, right: &str| {
+            let segments: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+            style(format!("{}{}{}", left, segments.join(middle), right)).dim().to_string()
+        };
+        let row_line = |row: &[String], base: &Style| {
+            let bar = style("│").dim().to_string();
+            let mut line = bar.clone();
+            for (c, cell) in row.iter().enumerate() {
+                let align = alignments.get(c).copied().unwrap_or(console::Alignment::Left);
+                let padded = console::pad_str(cell, widths[c], align, None);
+                line.push_str(&format!(" {} {}", style_inline_code(&padded, base), bar));
+            }
+            line
+        };
 
-fn install_node_package(package: &str) -> Result<(), String> {
-    println!("{} {}", style("Installing Node package:").bold().yellow(), style(package).white());
-    Command::new("npm")
-        .args(&["install", package])
-        .output()
-        .map_err(|e| e.to_string())?;
-    Ok(())
-}
+        let mut lines = vec![border("┌", "┬", "┐")];
+        lines.push(row_line(&cells[0], &Style::new().white().bold()));
+        lines.push(border("├", "┼", "┤"));
+        for row in &cells[1..] {
+            lines.push(row_line(row, &Style::new().white()));
+        }
+        lines.push(border("└", "┴", "┘"));
+        lines.join("\n")
+    }
 
-fn handle_python_error(error: &str, code: &str) -> Result<String, String> {
-    if error.contains("ModuleNotFoundError") {
-        let pkg = error
-            .split("No module named '")
-            .nth(1)
-            .and_then(|s| s.split('\'').next())
-            .ok_or_else(|| "Could not extract package name".to_string())?;
-        install_python_package(pkg)?;
-        execute_code_block(code, "python", None)
-    } else {
-        Err(error.to_string())
+    fn format_single(&mut self, line: &str) -> String {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            self.in_block = !self.in_block;
+            self.block_line = 0;
+            self.highlighter = if self.in_block {
+                highlighter_for(trimmed.trim_start_matches('`').trim())
+            } else {
+                None
+            };
+            style(line).cyan().to_string()
+        } else if self.in_block {
+            self.format_code_line(line)
+        } else if trimmed.is_empty() {
+            String::new()
+        } else if trimmed.starts_with('#') {
+            self.format_prose(line, &Style::new().yellow().bold())
+        } else if trimmed.starts_with('-') {
+            self.format_prose(line, &Style::new().green())
+        } else {
+            self.format_prose(line, &Style::new().white())
+        }
     }
-}
 
-fn handle_node_error(error: &str, code: &str) -> Result<String, String> {
-    if error.contains("Cannot find module") {
-        let pkg = error
-            .split("Cannot find module '")
-            .nth(1)
-            .and_then(|s| s.split('\'').next())
-            .ok_or_else(|| "Could not extract package name".to_string())?;
-        install_node_package(pkg)?;
-        execute_code_block(code, "javascript", None)
-    } else {
-        Err(error.to_string())
+    fn format_code_line(&mut self, line: &str) -> String {
+        self.block_line += 1;
+        let number = if appearance().line_numbers {
+            style(format!("{:>3} ", self.block_line)).dim().to_string()
+        } else {
+            String::new()
+        };
+        let Some(highlighter) = self.highlighter.as_mut() else {
+            return format!("{}{}", number, style(line).cyan());
+        };
+        // The newline-aware syntaxes expect each line to carry its `\n`
+        match highlighter.highlight_line(&format!("{}\n", line), syntax_set()) {
+            Ok(ranges) => format!(
+                "{}{}\x1b[0m",
+                number,
+                as_24_bit_terminal_escaped(&ranges, false).trim_end_matches('\n')
+            ),
+            Err(_) => format!("{}{}", number, style(line).cyan()),
+        }
     }
 }
 
-fn setup_react_environment(workdir: Option<&str>) -> Result<(), String> {
-    println!("{}", style("Setting up React environment...").bold().yellow());
-    
-    // Create React app using create-react-app
-    let mut cmd = Command::new("npx");
-    cmd.args(&["create-react-app", "react-app"]);
-    
-    if let Some(dir) = workdir {
-        cmd.current_dir(dir);
-    }
-    
-    let status = cmd
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .status()
-        .map_err(|e| e.to_string())?;
+/// Turns a response into output for one `OutputFormat`, a line at a time
+/// so streamed and whole responses come out the same. Json is reported
+/// whole by `print_json_report` and passes lines through here.
+///
+/// Reasoning models wrap their thinking in `<think>`...`</think>`. Outside
+/// plain and json output it is hidden, or dimmed with `--show-thinking`.
+struct Renderer {
+    format: OutputFormat,
+    formatter: ResponseFormatter,
+    /// Inside a fenced block, for closing a fence the model left open
+    in_block: bool,
+    /// Set inside a `<think>` section, holding its lines while they're hidden
+    thinking: Option<Vec<String>>,
+}
 
-    if !status.success() {
-        return Err("Failed to create React application".to_string());
+impl Renderer {
+    fn new(format: OutputFormat) -> Self {
+        Renderer {
+            format,
+            formatter: ResponseFormatter::for_terminal(),
+            in_block: false,
+            thinking: None,
+        }
     }
 
-    Ok(())
-}
+    /// The output for one line, or None while nothing is ready (table rows,
+    /// hidden thinking)
+    fn line(&mut self, line: &str) -> Option<String> {
+        let verbatim = matches!(self.format, OutputFormat::Plain | OutputFormat::Json);
+        if verbatim || (self.thinking.is_none() && !line.contains("<think>")) {
+            return self.text(line);
+        }
+
+        // A tag can share its line with text on either side
+        let mut out = Vec::new();
+        let mut rest = line;
+        loop {
+            if let Some(held) = self.thinking.as_mut() {
+                let (thought, after) = match rest.split_once("</think>") {
+                    Some((thought, after)) => (thought, Some(after)),
+                    None => (rest, None),
+                };
+                if !thought.trim().is_empty() {
+                    if appearance().show_thinking {
+                        out.push(self.thought(thought));
+                    } else {
+                        held.push(thought.to_string());
+                    }
+                }
+                let Some(after) = after else { break };
+                if !appearance().show_thinking && self.format == OutputFormat::Ansi {
+                    out.push(style(format!("{}Reasoning hidden (--show-thinking to see it)", icon("💭"))).dim().to_string());
+                }
+                self.thinking = None;
+                rest = after;
+            } else {
+                let (text, after) = match rest.split_once("<think>") {
+                    Some((text, after)) => (text, Some(after)),
+                    None => (rest, None),
+                };
+                if !text.trim().is_empty() {
+                    out.extend(self.text(text));
+                }
+                let Some(after) = after else { break };
+                self.thinking = Some(Vec::new());
+                rest = after;
+            }
+        }
+        (!out.is_empty()).then(|| out.join("\n"))
+    }
+
+    /// A line of reasoning shown with `--show-thinking`
+    fn thought(&self, text: &str) -> String {
+        match self.format {
+            OutputFormat::Markdown => format!("> {}", text.trim_end()),
+            _ => style(text.trim_end()).dim().italic().to_string(),
+        }
+    }
+
+    /// A line of the answer itself
+    fn text(&mut self, line: &str) -> Option<String> {
+        match self.format {
+            OutputFormat::Ansi => self.formatter.format_line(line),
+            OutputFormat::Markdown => {
+                let line = console::strip_ansi_codes(line).replace('\r', "");
+                if line.trim_start().starts_with("```") {
+                    self.in_block = !self.in_block;
+                }
+                Some(line)
+            }
+            OutputFormat::Plain | OutputFormat::Json => Some(line.to_string()),
+        }
+    }
+
+    /// Whatever is still held when the response ends
+    fn finish(&mut self) -> Option<String> {
+        // Thinking with no closing tag may hold the answer, so show it
+        if let Some(held) = self.thinking.take() {
+            let lines: Vec<String> = held.iter().map(|line| self.thought(line)).collect();
+            if !lines.is_empty() {
+                let rest = self.finish();
+                return Some(lines.into_iter().chain(rest).collect::<Vec<_>>().join("\n"));
+            }
+        }
+        match self.format {
+            OutputFormat::Ansi => self.formatter.finish(),
+            OutputFormat::Markdown if self.in_block => {
+                self.in_block = false;
+                Some("```".to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Render a whole response in `format`
+fn render(response: &str, format: OutputFormat) -> String {
+    let mut renderer = Renderer::new(format);
+    let mut rendered = String::new();
+    for line in response.lines() {
+        if let Some(line) = renderer.line(line) {
+            rendered.push_str(&line);
+            rendered.push('\n');
+        }
+    }
+    if let Some(rest) = renderer.finish() {
+        rendered.push_str(&rest);
+        rendered.push('\n');
+    }
+    rendered
+}
+
+/// The response without its `<think>` sections, for history and code
+/// blocks. An unclosed one is kept, as the answer may be inside it.
+fn strip_thinking(response: &str) -> String {
+    let mut answer = String::new();
+    let mut rest = response;
+    while let Some((text, after)) = rest.split_once("<think>") {
+        answer.push_str(text);
+        match after.split_once("</think>") {
+            Some((_, after)) => rest = after.strip_prefix('\n').unwrap_or(after),
+            None => {
+                rest = after;
+                break;
+            }
+        }
+    }
+    answer.push_str(rest);
+    answer
+}
+
+/// A response styled for the terminal
+fn format_response(response: &str) -> String {
+    render(response, OutputFormat::Ansi)
+}
+
+/// Commands handled locally rather than sent to the model
+const SPECIAL_COMMANDS: &[&str] = &[
+    "!clear",
+    "!context ",
+    "!copy",
+    "!copy-code",
+    "!edit",
+    "!exit",
+    "!explain",
+    "!extract ",
+    "!history",
+    "!quit",
+    "!models",
+    "!profile ",
+    "!rerun",
+    "!reset",
+    "!stop",
+    "!switch ",
+    "!system ",
+    "!tpl ",
+    "!save ",
+    "create-react-app",
+    "npm start",
+    "start-server ",
+];
+
+/// Tab completion for special commands, and for paths where a command
+/// expects one
+struct CodexCompleter {
+    files: FilenameCompleter,
+}
+
+impl CodexCompleter {
+    fn new() -> Self {
+        CodexCompleter { files: FilenameCompleter::new() }
+    }
+}
+
+impl Completer for CodexCompleter {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let previous_word = before.split_whitespace().rev().nth(usize::from(!before.ends_with(' ')));
+        if before.starts_with("!save ") || previous_word == Some("--workdir") {
+            return self.files.complete(line, pos, ctx);
+        }
+
+        let candidates = SPECIAL_COMMANDS
+            .iter()
+            .filter(|cmd| cmd.starts_with(before) && !before.is_empty())
+            .map(|cmd| Pair { display: cmd.trim_end().to_string(), replacement: cmd.to_string() })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CodexCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CodexCompleter {}
+
+impl Validator for CodexCompleter {}
+
+impl Helper for CodexCompleter {}
+
+type LineEditor = Editor<CodexCompleter, DefaultHistory>;
+
+fn history_path() -> Option<PathBuf> {
+    config::config_dir().map(|dir| dir.join("history.txt"))
+}
+
+fn create_editor() -> Result<LineEditor, CodexError> {
+    let mut editor = LineEditor::new().map_err(|e| CodexError::from(e.to_string()))?;
+    editor.set_helper(Some(CodexCompleter::new()));
+    if let Some(path) = history_path() {
+        // A missing history file just means this is the first session
+        let _ = editor.load_history(&path);
+    }
+    Ok(editor)
+}
+
+fn read_prompt_line(editor: &mut LineEditor, marker: Option<&str>) -> Option<String> {
+    let marker = marker
+        .map(|m| format!("{} ", style(m).bold().cyan()))
+        .unwrap_or_default();
+    match editor.readline(&marker) {
+        Ok(line) => Some(line),
+        // Ctrl+C at the prompt exits, as it always has, but through the end
+        // of `main` so the session is cleaned up
+        Err(ReadlineError::Interrupted) => {
+            QUIT_REQUESTED.store(true, Ordering::SeqCst);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Read one prompt. A line of just `"""` starts a block that runs until the
+/// next `"""`, and a trailing `\` continues the prompt on the next line.
+/// `quiet` hides the `>` markers so they don't mix into `--json` output.
+/// Returns None once input is exhausted (Ctrl-D or closed stdin), or on
+/// Ctrl-C, even halfway through a prompt.
+fn get_user_input(editor: &mut LineEditor, quiet: bool) -> Option<String> {
+    let (marker, continuation) = if quiet { (None, None) } else { (Some(">"), Some("…")) };
+    let first = read_prompt_line(editor, marker)?;
+
+    if first.trim() == "\"\"\"" {
+        let mut lines = Vec::new();
+        while let Some(line) = read_prompt_line(editor, continuation) {
+            if line.trim() == "\"\"\"" {
+                break;
+            }
+            lines.push(line);
+        }
+        if QUIT_REQUESTED.load(Ordering::SeqCst) {
+            return None;
+        }
+        let input = lines.join("\n");
+        remember(editor, &input);
+        return Some(input);
+    }
+
+    let mut input = first.trim_end().to_string();
+    while let Some(stripped) = input.strip_suffix('\\') {
+        let Some(next) = read_prompt_line(editor, continuation) else {
+            if QUIT_REQUESTED.load(Ordering::SeqCst) {
+                return None;
+            }
+            input = stripped.to_string();
+            break;
+        };
+        input = format!("{}\n{}", stripped, next.trim_end());
+    }
+    remember(editor, &input);
+    Some(input)
+}
+
+/// Add a prompt to the recall history and persist it for future sessions
+fn remember(editor: &mut LineEditor, input: &str) {
+    if input.trim().is_empty() {
+        return;
+    }
+    let _ = editor.add_history_entry(input);
+    if let Some(path) = history_path() {
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = editor.save_history(&path);
+    }
+}
+
+/// Split a `!` command into its argv, honouring shell quoting and escapes
+/// (`echo "hello world"` is two arguments, not three)
+fn parse_command(command: &str) -> Result<Vec<String>, CodexError> {
+    shell_words::split(command).map_err(|_| format!("Unbalanced quotes in command: {}", command).into())
+}
+
+/// Commands that change files, permissions or remotes. Entries with two
+/// words match the subcommand too, so `git push` asks but `git status`
+/// doesn't.
+const MUTATING_COMMANDS: &[&str] = &[
+    "rm", "rmdir", "mv", "cp", "dd", "mkfs", "chmod", "chown", "chgrp", "truncate", "shred", "ln",
+    "kill", "killall", "pkill", "shutdown", "reboot",
+    "git push", "git reset", "git clean", "git checkout", "git rebase",
+    "npm publish", "cargo publish",
+];
+
+/// Shell syntax that chains commands, substitutes one into another or
+/// redirects to files. What follows it can't be judged from the first word.
+const SHELL_OPERATORS: &[&str] = &[";", "&", "|", "`", "$(", "<", ">", "\n"];
+
+/// Programs that run another command given as their arguments
+const COMMAND_RUNNERS: &[&str] = &[
+    "sh", "bash", "zsh", "dash", "ksh", "fish", "eval", "exec", "xargs", "env", "nohup", "nice", "time",
+    "timeout", "watch", "su",
+];
+
+/// Whether `command` may change something and should be confirmed first:
+/// a mutating program, anything chained, substituted or redirected (even
+/// `ls | xargs rm`), another command run through a shell or wrapper, and
+/// anything that doesn't parse
+fn is_mutating_command(command: &str) -> bool {
+    if SHELL_OPERATORS.iter().any(|op| command.contains(op)) {
+        return true;
+    }
+    match shell_words::split(command) {
+        Ok(argv) => is_mutating_program(&argv),
+        Err(_) => true,
+    }
+}
+
+fn is_mutating_program(argv: &[String]) -> bool {
+    // Look through privilege wrappers at the real command
+    let argv: Vec<&str> = argv
+        .iter()
+        .map(String::as_str)
+        .skip_while(|word| matches!(*word, "sudo" | "doas"))
+        .collect();
+    let Some(program) = argv.first() else {
+        return false;
+    };
+    // `/bin/rm`, and `mkfs.ext4` and friends
+    let program = Path::new(program).file_name().and_then(|name| name.to_str()).unwrap_or(program);
+    let program = program.split('.').next().unwrap_or(program);
+    if COMMAND_RUNNERS.contains(&program) {
+        return true;
+    }
+    if program == "find" && argv.iter().any(|word| matches!(*word, "-delete" | "-exec" | "-execdir" | "-ok" | "-okdir")) {
+        return true;
+    }
+    MUTATING_COMMANDS.iter().any(|entry| match entry.split_once(' ') {
+        Some((cmd, sub)) => program == cmd && argv.get(1) == Some(&sub),
+        None => program == *entry,
+    })
+}
+
+/// The flag that makes `shell` run a command string
+fn shell_command_flag(shell: &str) -> &'static str {
+    let name = Path::new(shell)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match name.as_str() {
+        "cmd" => "/C",
+        "powershell" | "pwsh" => "-Command",
+        _ => "-c",
+    }
+}
+
+/// What runs for a `!` command: `shell -c command` so pipes, globs and
+/// variables work, or with no shell the command's own words
+fn command_argv(command: &str, shell: Option<&str>) -> Result<Vec<String>, CodexError> {
+    match shell {
+        Some(shell) => Ok(vec![shell.to_string(), shell_command_flag(shell).to_string(), command.to_string()]),
+        None => parse_command(command),
+    }
+}
+
+/// What a `!` command printed, and how it exited
+struct CommandOutput {
+    stdout: String,
+    stderr: String,
+    status: ExitStatus,
+}
+
+impl CommandOutput {
+    /// Both streams and the exit status as one text, for `!explain`
+    fn summary(&self) -> String {
+        let mut text = self.stdout.clone();
+        if !self.stderr.trim().is_empty() {
+            text.push_str(&format!("\n[stderr]\n{}", self.stderr));
+        }
+        text.push_str(&format!("\n[{}]", self.status));
+        text
+    }
+}
+
+/// Run a `!` command. Only failing to start it is an error; a command that
+/// exits non-zero is reported through its `CommandOutput`.
+fn execute_command(command: &str, shell: Option<&str>) -> Result<CommandOutput, CodexError> {
+    let parts = command_argv(command, shell)?;
+    if parts.is_empty() || command.trim().is_empty() {
+        return Err("Empty command".into());
+    }
+    debug!("running command {:?}", parts);
+
+    let output = Command::new(&parts[0])
+        .args(&parts[1..])
+        .output()
+        ?;
+
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        status: output.status,
+    })
+}
+
+/// Print a `!` command's stdout in the output block, its stderr in red below
+/// it, then the exit status. Raw mode passes the two streams through to ours.
+fn print_command_output(out: &CommandOutput, raw: bool) {
+    if raw {
+        print!("{}", out.stdout);
+        eprint!("{}", out.stderr);
+        return;
+    }
+    print_heading(style("Command output:").bold().green());
+    println!();
+    if !out.stdout.trim().is_empty() {
+        println!("{}", out.stdout.trim_end());
+    }
+    if !out.stderr.trim().is_empty() {
+        println!("{}", style("stderr:").bold().red());
+        for line in out.stderr.trim_end().lines() {
+            println!("{}", style(line).red().dim());
+        }
+    }
+    print_divider();
+    let status = match out.status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => out.status.to_string(),
+    };
+    if out.status.success() {
+        println!("{}", style(format!("{}{}", icon("✓"), status)).green().dim());
+    } else {
+        println!("{}", style(format!("{}{}", icon("✗"), status)).red().bold());
+    }
+}
+
+/// A fenced code block pulled out of a model response
+#[derive(Serialize)]
+struct CodeBlock {
+    lang: String,
+    code: String,
+    /// The file the model meant the block for, from a ```lang:path fence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    /// The response ended before the closing fence (usually truncated output)
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    unterminated: bool,
+}
+
+/// Guess the language of an untagged code block from simple signals:
+/// shebangs, opening tags and telltale keywords
+fn detect_language(code: &str) -> Option<String> {
+    let lines: Vec<&str> = code.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    let first = *lines.first()?;
+    let any = |pred: &dyn Fn(&str) -> bool| lines.iter().any(|l| pred(l));
+    let lang = |name: &str| Some(name.to_string());
+
+    if let Some(shebang) = first.strip_prefix("#!") {
+        return [("python", "python"), ("node", "javascript"), ("ruby", "ruby"), ("php", "php"), ("sh", "bash")]
+            .iter()
+            .find(|(needle, _)| shebang.contains(needle))
+            .and_then(|(_, name)| lang(name));
+    }
+    if first.starts_with('{') && code.contains("\"nbformat\"") {
+        return lang("ipynb");
+    }
+    let lower = code.to_lowercase();
+    if lower.contains("<?php") {
+        return lang("php");
+    }
+    if lower.contains("<!doctype html") || lower.contains("<html") {
+        return lang("html");
+    }
+    if code.contains("fn main") || any(&|l| l.starts_with("use std::") || l.starts_with("let mut ")) {
+        return lang("rust");
+    }
+    if first == "package main" {
+        return lang("go");
+    }
+    if any(&|l| l.starts_with("#include")) {
+        let cpp = ["std::", "iostream", "cout", "namespace"].iter().any(|k| code.contains(k));
+        return lang(if cpp { "cpp" } else { "c" });
+    }
+    if any(&|l| {
+        (l.starts_with("def ") && l.ends_with(':'))
+            || l.starts_with("import ")
+            || (l.starts_with("from ") && l.contains(" import "))
+            || l.starts_with("print(")
+    }) {
+        return lang("python");
+    }
+    if any(&|l| l.starts_with("puts ") || l.starts_with("require '")) {
+        return lang("ruby");
+    }
+    if any(&|l| {
+        l.starts_with("function ") || l.starts_with("const ") || l.contains("=>") || l.contains("console.log") || l.contains("require(")
+    }) {
+        return lang("javascript");
+    }
+    let shell_commands = ["echo ", "cd ", "ls", "mkdir ", "npm ", "pip ", "sudo ", "export ", "apt", "brew ", "cargo ", "git "];
+    if shell_commands.iter().any(|c| first.starts_with(c)) {
+        return lang("bash");
+    }
+    None
+}
+
+/// Ask which language an untagged block is in; empty input skips it
+fn ask_language() -> Option<String> {
+    println!(
+        "\n{} {}",
+        style("Could not tell this block's language.").bold().yellow(),
+        style("Language to run it as (e.g. python, bash), or Enter to skip:").dim()
+    );
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok()?;
+    let answer = answer.trim();
+    (!answer.is_empty()).then(|| answer.to_lowercase())
+}
+
+/// Remove up to `indent` bytes of leading whitespace, leaving deeper
+/// indentation (the code's own) untouched
+fn strip_indent(line: &str, indent: usize) -> &str {
+    let cut = line
+        .char_indices()
+        .take_while(|(i, c)| *i < indent && c.is_whitespace())
+        .map(|(i, c)| i + c.len_utf8())
+        .last()
+        .unwrap_or(0);
+    &line[cut..]
+}
+
+fn extract_code_blocks(response: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current = String::new();
+    let mut in_block = false;
+    let mut lang = String::new();
+    let mut filename = None;
+    // Fences inside list items are indented; the body carries the same indent
+    let mut indent = 0;
+
+    for line in response.lines() {
+        if line.trim().starts_with("```") {
+            if in_block {
+                blocks.push(CodeBlock {
+                    lang: lang.clone(),
+                    code: current.clone(),
+                    filename: filename.take(),
+                    unterminated: false,
+                });
+                current.clear();
+                lang.clear();
+                in_block = false;
+            } else {
+                in_block = true;
+                // ```python:src/app.py names the file the block belongs in
+                let info = line.trim().trim_start_matches("```");
+                (lang, filename) = match info.split_once(':') {
+                    Some((name, path)) => {
+                        let path = path.trim();
+                        (name.trim().to_string(), (!path.is_empty()).then(|| path.to_string()))
+                    }
+                    None => (info.to_string(), None),
+                };
+                indent = line.len() - line.trim_start().len();
+            }
+        } else if in_block {
+            current.push_str(strip_indent(line, indent));
+            current.push('\n');
+        }
+    }
+
+    // Keep whatever we have of a block the model never closed
+    if in_block {
+        blocks.push(CodeBlock { lang, code: current, filename, unterminated: true });
+    }
+
+    blocks
+}
+
+/// Shell snippets that can wreck a machine if a model gets creative. The
+/// flag marks patterns that only count when they end at a word boundary, so
+/// `rm -rf /` matches but `rm -rf /tmp/build` doesn't.
+const DANGEROUS_PATTERNS: &[(&str, bool)] = &[
+    ("rm -rf /", true),
+    ("rm -fr /", true),
+    ("rm -rf /*", true),
+    ("rm -rf ~", true),
+    ("rm -rf *", true),
+    ("rm -rf $home", true),
+    ("mkfs", false),
+    ("dd if=", false),
+    ("> /dev/sd", false),
+    (":(){ :|:& };:", false),
+    ("chmod -r 777 /", true),
+    ("shutdown", false),
+    ("reboot", false),
+];
+
+fn find_dangerous_pattern(code: &str) -> Option<&'static str> {
+    let normalized = code.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    DANGEROUS_PATTERNS
+        .iter()
+        .find(|(pattern, bounded)| {
+            normalized.match_indices(pattern).any(|(i, _)| {
+                let rest = &normalized[i + pattern.len()..];
+                !bounded || rest.is_empty() || rest.starts_with([' ', ';', '&', '|'])
+            })
+        })
+        .map(|(pattern, _)| *pattern)
+}
+
+/// A second Ctrl-C within this long of the first exits the program
+const CANCEL_WINDOW: Duration = Duration::from_secs(2);
+
+/// What a Ctrl-C would cancel, and whether it has
+struct CancelState {
+    /// The processes of the model call or code block running now, or None
+    /// for an HTTP request, which notices `cancelled` itself
+    running: Mutex<Vec<Option<u32>>>,
+    cancelled: AtomicBool,
+}
+
+impl CancelState {
+    const fn new() -> Self {
+        CancelState { running: Mutex::new(Vec::new()), cancelled: AtomicBool::new(false) }
+    }
+
+    /// Forget an earlier Ctrl-C. Done once per response rather than per
+    /// registration, so a cancel isn't lost when a block registers its own
+    /// processes (venv setup, then the run) after it
+    fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+}
+
+static CANCEL: CancelState = CancelState::new();
+static LAST_INTERRUPT: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Set by Ctrl-C at the prompt, which ends the session like Ctrl-D but
+/// exits with 130
+static QUIT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Running servers and temp pages, mirrored from the session so a Ctrl-C
+/// that exits on the spot (where `Session`'s Drop doesn't run) still
+/// cleans them up
+static EXIT_CLEANUP: Mutex<ExitCleanup> = Mutex::new(ExitCleanup { servers: Vec::new(), pages: Vec::new() });
+
+struct ExitCleanup {
+    servers: Vec<u32>,
+    pages: Vec<PathBuf>,
+}
+
+/// Stop the session's servers and remove its temp pages, then exit
+fn exit_after_cleanup(code: i32) -> ! {
+    if let Ok(cleanup) = EXIT_CLEANUP.lock() {
+        for pid in &cleanup.servers {
+            kill_process_tree(*pid);
+        }
+        for page in &cleanup.pages {
+            let _ = fs::remove_file(page);
+        }
+    }
+    std::process::exit(code)
+}
+
+/// Ctrl-C while a model call or code block runs kills just that process
+/// and returns to the prompt; with nothing running, or pressed twice in
+/// quick succession, it exits as before
+fn install_interrupt_handler() {
+    let result = ctrlc::set_handler(|| {
+        let running = CANCEL.running.lock().map(|pids| pids.clone()).unwrap_or_default();
+        let mut last = LAST_INTERRUPT.lock().unwrap_or_else(|e| e.into_inner());
+        if running.is_empty() || last.is_some_and(|at| at.elapsed() < CANCEL_WINDOW) {
+            exit_after_cleanup(130);
+        }
+        *last = Some(Instant::now());
+        CANCEL.cancelled.store(true, Ordering::SeqCst);
+        for pid in running.into_iter().flatten() {
+            kill_process_tree(pid);
+        }
+        eprintln!("\n{}", style("Cancelled. Press Ctrl+C again to exit.").dim());
+    });
+    if let Err(e) = result {
+        debug!("could not install Ctrl-C handler: {}", e);
+    }
+}
+
+/// Registers a child (or an HTTP request) as the operation Ctrl-C
+/// cancels, until dropped
+struct Cancellable {
+    pid: Option<u32>,
+    state: &'static CancelState,
+}
+
+impl Cancellable {
+    fn track(child: &Child) -> Self {
+        Self::register(&CANCEL, Some(child.id()))
+    }
+
+    /// An in-process request, which has to check `cancelled` as it goes
+    fn request() -> Self {
+        Self::register(&CANCEL, None)
+    }
+
+    fn register(state: &'static CancelState, pid: Option<u32>) -> Self {
+        if let Ok(mut pids) = state.running.lock() {
+            pids.push(pid);
+        }
+        Cancellable { pid, state }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Cancellable {
+    fn drop(&mut self) {
+        if let Ok(mut pids) = self.state.running.lock() {
+            if let Some(i) = pids.iter().position(|pid| *pid == self.pid) {
+                pids.remove(i);
+            }
+        }
+    }
+}
+
+/// Wait for `child`, killing it if it runs past `timeout`
+fn wait_with_timeout(child: &mut Child, timeout: Option<Duration>) -> Result<ExitStatus, CodexError> {
+    let cancel = Cancellable::track(child);
+    let status = match timeout {
+        None => child.wait()?,
+        Some(limit) => match child.wait_timeout(limit)? {
+            Some(status) => status,
+            None => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CodexError::Timeout(limit));
+            }
+        },
+    };
+    if cancel.cancelled() {
+        return Err(CodexError::Cancelled);
+    }
+    Ok(status)
+}
+
+/// Ask a yes/no question on stdin; anything but "y" counts as no
+fn confirm(question: &str) -> bool {
+    println!("\n{} (y/n)", style(question).bold().yellow());
+    let mut ans = String::new();
+    io::stdin().read_line(&mut ans).is_ok() && ans.trim().eq_ignore_ascii_case("y")
+}
+
+/// Answer to the per-block prompt when a response has several code blocks
+enum BlockChoice {
+    Run,
+    Skip,
+    /// Run this block and the rest without asking again
+    RunAll,
+    /// Run nothing more from this response
+    Quit,
+}
+
+fn confirm_block() -> BlockChoice {
+    println!(
+        "\n{} {}",
+        style("Run this block? (y/n/a/q)").bold().yellow(),
+        style("y run, n skip, a run all remaining, q stop").dim()
+    );
+    let mut ans = String::new();
+    if io::stdin().read_line(&mut ans).unwrap_or(0) == 0 {
+        return BlockChoice::Quit;
+    }
+    match ans.trim().to_lowercase().as_str() {
+        "y" | "yes" => BlockChoice::Run,
+        "a" | "all" => BlockChoice::RunAll,
+        "q" | "quit" => BlockChoice::Quit,
+        _ => BlockChoice::Skip,
+    }
+}
+
+/// Like `Command::output`, but for an already spawned child with piped
+/// stdout/stderr and subject to `timeout`
+fn output_with_timeout(child: Child, timeout: Option<Duration>) -> Result<Output, CodexError> {
+    collect_output(child, timeout, false)
+}
+
+/// Like `output_with_timeout`, but also echoes stdout to the terminal line
+/// by line as it arrives. Stderr is only collected, since callers report it
+/// as part of the error.
+fn tee_output_with_timeout(child: Child, timeout: Option<Duration>) -> Result<Output, CodexError> {
+    collect_output(child, timeout, true)
+}
+
+fn collect_output(mut child: Child, timeout: Option<Duration>, echo: bool) -> Result<Output, CodexError> {
+    // Drain the pipes on their own threads so a chatty child can't block
+    // on a full pipe while we wait for it
+    let drain = |pipe: Option<Box<dyn Read + Send>>, echo: bool| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let Some(pipe) = pipe else {
+                return buf;
+            };
+            let mut reader = BufReader::new(pipe);
+            let mut line = Vec::new();
+            while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                if echo {
+                    let _ = io::stdout().write_all(&line).and_then(|_| io::stdout().flush());
+                }
+                buf.append(&mut line);
+            }
+            buf
+        })
+    };
+    let stdout = drain(child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>), echo);
+    let stderr = drain(child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>), false);
+
+    let status = wait_with_timeout(&mut child, timeout)?;
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Start a program for a code block. With `capture` its output is piped so
+/// it can be echoed and kept; otherwise it gets the terminal, which
+/// interactive programs need.
+fn spawn_program(cmd: &mut Command, capture: bool) -> io::Result<Child> {
+    if capture {
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    } else {
+        cmd.stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit());
+    }
+    cmd.spawn()
+}
+
+/// The usual way to install Ollama on this platform
+fn ollama_install_hint() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "brew install ollama, or download it from https://ollama.com/download"
+    } else if cfg!(windows) {
+        "winget install Ollama.Ollama, or download it from https://ollama.com/download"
+    } else {
+        "curl -fsSL https://ollama.com/install.sh | sh"
+    }
+}
+
+/// Explain a failed spawn, turning "not found" into an install hint
+fn spawn_error(e: io::Error, name: &str, url: &str) -> CodexError {
+    match e.kind() {
+        io::ErrorKind::NotFound => CodexError::InterpreterNotFound { name: name.to_string(), hint: url.to_string() },
+        _ => e.into(),
+    }
+}
+
+/// Wait for a program started by `spawn_program`. Captured stdout is echoed
+/// as it arrives; without capture the returned output is empty.
+fn finish_program(mut child: Child, timeout: Option<Duration>, capture: bool) -> Result<Output, CodexError> {
+    if capture {
+        tee_output_with_timeout(child, timeout)
+    } else {
+        let status = wait_with_timeout(&mut child, timeout)?;
+        Ok(Output { status, stdout: Vec::new(), stderr: Vec::new() })
+    }
+}
+
+/// Turn a finished program into the code block result: its stdout on
+/// success, or `what` plus the exit status and any captured stderr
+fn program_result(out: Output, what: &str) -> Result<String, CodexError> {
+    if out.status.success() {
+        return Ok(String::from_utf8_lossy(&out.stdout).to_string());
+    }
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if stderr.trim().is_empty() {
+        Err(CodexError::Runtime(format!("{} exited with status: {}", what, out.status)))
+    } else {
+        Err(CodexError::Runtime(format!("{} exited with status: {}\n{}", what, out.status, stderr.trim_end())))
+    }
+}
+
+/// Compile a C or C++ source with the first of `compilers` that exists
+fn compile_c(compilers: &[&str], source: &Path, binary: &Path, base: &Path, timeout: Option<Duration>) -> Result<(), CodexError> {
+    for compiler in compilers {
+        debug!("compiling {} with {}", source.display(), compiler);
+        let child = match Command::new(compiler)
+            .arg(source)
+            .arg("-o")
+            .arg(binary)
+            .current_dir(base)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("{} not found, trying the next compiler", compiler);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let out = output_with_timeout(child, timeout)?;
+        if !out.status.success() {
+            return Err(CodexError::CompileFailed {
+                compiler: compiler.to_string(),
+                output: String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+            });
+        }
+        return Ok(());
+    }
+    Err(CodexError::InterpreterNotFound {
+        name: compilers[0].to_string(),
+        hint: format!("tried {}", compilers.join(", ")),
+    })
+}
+
+/// Path to the interpreter inside the venv under `base`
+fn venv_python(base: &Path) -> PathBuf {
+    if cfg!(windows) {
+        base.join("venv").join("Scripts").join("python.exe")
+    } else {
+        base.join("venv").join("bin").join("python")
+    }
+}
+
+/// Written into the venv once setup has succeeded, so later blocks can skip it
+const VENV_READY_MARKER: &str = ".codexcli_ready";
+
+fn setup_python_environment(base: &Path) -> Result<(), CodexError> {
+    let marker = base.join("venv").join(VENV_READY_MARKER);
+    if marker.exists() && venv_python(base).exists() {
+        debug!("reusing venv in {}", base.display());
+        return Ok(());
+    }
+
+    let spinner = show_spinner("Setting up Python environment...");
+    let result = create_python_environment(base, &spinner);
+    spinner.finish_and_clear();
+    result?;
+
+    // Not fatal: without the marker we just set up again next time
+    let _ = fs::write(&marker, "");
+    show_success("Python environment setup complete");
+    Ok(())
+}
+
+fn create_python_environment(base: &Path, spinner: &ProgressBar) -> Result<(), CodexError> {
+    if !base.join("venv").exists() {
+        debug!("creating venv in {}", base.display());
+        let result = Command::new("python")
+            .args(["-m", "venv", "venv"])
+            .current_dir(base)
+            .output();
+
+        match result {
+            Ok(_output) => (),
+            Err(_) => {
+                spinner.suspend(|| show_error_recovery("Python not found, attempting to install..."));
+                // Try to install Python
+                if cfg!(windows) {
+                    Command::new("winget")
+                        .args(["install", "Python.Python"])
+                        .output()
+                        ?;
+                } else {
+                    Command::new("sudo")
+                        .args(["apt-get", "install", "python3"])
+                        .output()
+                        ?;
+                }
+                // Retry venv creation
+                Command::new("python")
+                    .args(["-m", "venv", "venv"])
+                    .current_dir(base)
+                    .output()
+                    ?;
+            }
+        }
+    }
+
+    let python_path = venv_python(base);
+
+    // Install common packages with retry logic
+    let packages = ["pip", "setuptools", "wheel"];
+    for package in packages.iter() {
+        let mut attempts = 0;
+        while attempts < 3 {
+            debug!("pip install --upgrade {} (attempt {})", package, attempts + 1);
+            let result = Command::new(&python_path)
+                .args(["-m", "pip", "install", "--upgrade", package])
+                .current_dir(base)
+                .output();
+            if let Ok(out) = &result {
+                trace!("pip output:\n{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+            }
+
+            match result {
+                Ok(_output) if _output.status.success() => break,
+                Ok(_output) => {
+                    spinner.suspend(|| show_warning(&format!("Failed to install {}, retrying...", package)));
+                    attempts += 1;
+                    if attempts == 3 {
+                        return Err(format!("Failed to install {} after 3 attempts", package).into());
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Delete the workspace venv so the next Python block rebuilds it
+fn reset_python_environment(base: &Path) -> Result<(), CodexError> {
+    let venv = base.join("venv");
+    if venv.exists() {
+        fs::remove_dir_all(&venv).map_err(|e| format!("{}: {}", venv.display(), e))?;
+    }
+    Ok(())
+}
+
+/// How many times a package install is tried before giving up
+const INSTALL_ATTEMPTS: u32 = 3;
+
+/// Run an install command in `base` until it succeeds, waiting 1 s, then
+/// 2 s, between attempts (network hiccups are the usual cause). The final
+/// failure carries the tool's own error output.
+fn install_with_retry(mut command: Command, package: &str, base: &Path, spinner: &ProgressBar) -> Result<(), CodexError> {
+    command.current_dir(base);
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        debug!("{:?} (attempt {})", command, attempt);
+        let out = command.output()?;
+        trace!("{} output:\n{}{}", program, String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+        if out.status.success() {
+            return Ok(());
+        }
+        if attempt == INSTALL_ATTEMPTS {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            return Err(format!(
+                "Failed to install {} after {} attempts ({}):\n{}",
+                package,
+                INSTALL_ATTEMPTS,
+                out.status,
+                stderr.trim()
+            )
+            .into());
+        }
+        spinner.suspend(|| show_warning(&format!("Failed to install {}, retrying in {} s...", package, delay.as_secs())));
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+fn install_python_package(package: &str, base: &Path) -> Result<(), CodexError> {
+    let spinner = show_spinner(&format!("Installing Python package: {}", package));
+    debug!("pip install {} into {}", package, base.join("venv").display());
+    let mut command = Command::new(venv_python(base));
+    command.args(["-m", "pip", "install", package]);
+    let result = install_with_retry(command, package, base, &spinner);
+    spinner.finish_and_clear();
+    result
+}
+
+fn setup_node_environment(base: &Path) -> Result<(), CodexError> {
+    // Create package.json if it doesn't exist
+    if !base.join("package.json").exists() {
+        let spinner = show_spinner("Setting up Node.js environment...");
+        let result = Command::new("npm")
+            .args(["init", "-y"])
+            .current_dir(base)
+            .output();
+        spinner.finish_and_clear();
+        result?;
+    }
+    Ok(())
+}
+
+fn install_node_package(package: &str, base: &Path) -> Result<(), CodexError> {
+    let spinner = show_spinner(&format!("Installing Node package: {}", package));
+    let mut command = Command::new("npm");
+    command.args(["install", package]);
+    let result = install_with_retry(command, package, base, &spinner).map_err(|e| match e {
+        CodexError::Io(e) => spawn_error(e, "npm", "https://nodejs.org"),
+        e => e,
+    });
+    spinner.finish_and_clear();
+    result
+}
+
+/// Upper bound on install-and-retry rounds for a single script
+const MAX_AUTO_INSTALLS: usize = 5;
+
+/// Import names whose PyPI package is called something else
+static PYPI_NAME_MAP: OnceLock<HashMap<&'static str, &'static str>> = OnceLock::new();
+
+fn pypi_name_map() -> &'static HashMap<&'static str, &'static str> {
+    PYPI_NAME_MAP.get_or_init(|| {
+        HashMap::from([
+            ("sklearn", "scikit-learn"),
+            ("yaml", "PyYAML"),
+            ("cv2", "opencv-python"),
+            ("PIL", "Pillow"),
+            ("bs4", "beautifulsoup4"),
+            ("dotenv", "python-dotenv"),
+            ("dateutil", "python-dateutil"),
+            ("jwt", "PyJWT"),
+            ("Crypto", "pycryptodome"),
+            ("serial", "pyserial"),
+            ("usb", "pyusb"),
+            ("magic", "python-magic"),
+            ("docx", "python-docx"),
+            ("pptx", "python-pptx"),
+            ("attr", "attrs"),
+            ("OpenSSL", "pyOpenSSL"),
+            ("skimage", "scikit-image"),
+            ("fitz", "PyMuPDF"),
+        ])
+    })
+}
+
+/// The `pip install` name for an import, e.g. `sklearn` -> `scikit-learn`
+fn resolve_pip_name(import: &str) -> &str {
+    pypi_name_map().get(import).copied().unwrap_or(import)
+}
+
+/// Top-level module named in a `ModuleNotFoundError`, e.g. `foo` for
+/// "No module named 'foo.bar'"
+fn missing_python_module(stderr: &str) -> Option<&str> {
+    stderr
+        .split("No module named '")
+        .nth(1)
+        .and_then(|s| s.split('\'').next())
+        .and_then(|s| s.split('.').next())
+}
+
+/// Run `file` non-interactively, streaming its output live while keeping a
+/// copy for error analysis
+fn run_python_file(python: &Path, file: &Path, base: &Path, timeout: Option<Duration>) -> Result<Output, CodexError> {
+    let child = Command::new(python)
+        .arg(file)
+        .current_dir(base)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        ?;
+    tee_output_with_timeout(child, timeout)
+}
+
+/// Install missing modules one at a time, re-running the script after each,
+/// until it stops failing on imports. Returns the output of the last run.
+/// With `autoinstall` off, nothing is installed and the error says what to
+/// install instead.
+fn handle_python_error(
+    mut out: Output,
+    file: &Path,
+    workspace: &Workspace,
+    timeout: Option<Duration>,
+    autoinstall: bool,
+) -> Result<Output, CodexError> {
+    let python = venv_python(workspace.path());
+    let mut installed: Vec<String> = Vec::new();
+
+    for _ in 0..MAX_AUTO_INSTALLS {
+        let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+        if !stderr.contains("ModuleNotFoundError") {
+            break;
+        }
+
+        let module = missing_python_module(&stderr)
+            .ok_or_else(|| CodexError::from("Could not extract package name"))?;
+        let package = resolve_pip_name(module).to_string();
+        if installed.contains(&package) {
+            return Err(format!("Installed {} but '{}' is still missing", package, module).into());
+        }
+        if !autoinstall {
+            return Err(format!(
+                "Missing Python module '{}'. Install it with: {} -m pip install {}",
+                module,
+                python.display(),
+                package
+            )
+            .into());
+        }
+
+        install_python_package(&package, workspace.path())?;
+        installed.push(package);
+        out = run_python_file(&python, file, workspace.path(), timeout)?;
+    }
+
+    if String::from_utf8_lossy(&out.stderr).contains("ModuleNotFoundError") {
+        return Err(format!(
+            "Still missing modules after installing {} packages: {}",
+            installed.len(),
+            installed.join(", ")
+        ).into());
+    }
+    if !installed.is_empty() {
+        show_success(&format!("Installed missing packages: {}", installed.join(", ")));
+    }
+    Ok(out)
+}
+
+fn handle_node_error(
+    error: &str,
+    code: &str,
+    filename: Option<&str>,
+    session: &mut Session,
+    config: &ResolvedConfig,
+) -> Result<String, CodexError> {
+    if error.contains("Cannot find module") {
+        let pkg = error
+            .split("Cannot find module '")
+            .nth(1)
+            .and_then(|s| s.split('\'').next())
+            .ok_or_else(|| CodexError::from("Could not extract package name"))?;
+        if !config.autoinstall {
+            return Err(format!(
+                "Missing Node module '{}'. Install it with: npm install {} (in {})",
+                pkg,
+                pkg,
+                session.workspace.path().display()
+            )
+            .into());
+        }
+        install_node_package(pkg, session.workspace.path())?;
+        execute_code_block(code, "javascript", filename, session, config)
+    } else {
+        Err(CodexError::Runtime(error.to_string()))
+    }
+}
+
+fn setup_react_environment(workspace: &Workspace) -> Result<(), CodexError> {
+    println!("{}", style("Setting up React environment...").bold().yellow());
+    
+    // Create React app using create-react-app
+    let mut cmd = Command::new("npx");
+    cmd.args(["create-react-app", "react-app"]);
+    cmd.current_dir(workspace.path());
+    
+    let status = cmd
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        ?;
+
+    if !status.success() {
+        return Err("Failed to create React application".into());
+    }
+
+    Ok(())
+}
+
+fn start_react_server(workspace: &Workspace) -> Result<(String, ServerProcess), CodexError> {
+    println!("{}", style("Starting React development server...").bold().yellow());
+    
+    let app_dir = workspace.path().join("react-app");
+    if !app_dir.exists() {
+        return Err(format!("No React app found at {}. Run create-react-app first.", app_dir.display()).into());
+    }
 
-fn start_react_server(workdir: Option<&str>) -> Result<String, String> {
-    println!("{}", style("Starting React development server...").bold().yellow());
-    
     let mut cmd = Command::new("npm");
-    cmd.args(&["start"]);
+    cmd.args(["start"]);
+    cmd.current_dir(&app_dir);
+    
+    let child = cmd
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .spawn()
+        ?;
+    let server = ServerProcess::new("npm start", child);
+
+    Ok((
+        format!("React development server started (pid {}). Use !stop to stop it.", server.pid()),
+        server,
+    ))
+}
+
+/// A long-running server started from the CLI. It is stopped by `!stop`,
+/// or when the session ends.
+struct ServerProcess {
+    name: String,
+    child: Child,
+    /// Set for static file servers, which HTML blocks can reuse
+    port: Option<u16>,
+}
+
+impl ServerProcess {
+    fn new(name: &str, child: Child) -> Self {
+        if let Ok(mut cleanup) = EXIT_CLEANUP.lock() {
+            cleanup.servers.push(child.id());
+        }
+        ServerProcess { name: name.to_string(), child, port: None }
+    }
+
+    fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Kill the server and anything it spawned; `npm start` runs the real
+    /// dev server as a grandchild
+    fn stop(&mut self) {
+        if let Ok(mut cleanup) = EXIT_CLEANUP.lock() {
+            cleanup.servers.retain(|pid| *pid != self.pid());
+        }
+        if matches!(self.child.try_wait(), Ok(Some(_))) {
+            return;
+        }
+        kill_process_tree(self.pid());
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn kill_process_tree(pid: u32) {
+    if cfg!(windows) {
+        let _ = Command::new("taskkill").args(["/T", "/F", "/PID", &pid.to_string()]).output();
+        return;
+    }
+    // Children first, so none get reparented before we find them
+    if let Ok(out) = Command::new("pgrep").args(["-P", &pid.to_string()]).output() {
+        for child in String::from_utf8_lossy(&out.stdout).split_whitespace() {
+            if let Ok(child) = child.parse() {
+                kill_process_tree(child);
+            }
+        }
+    }
+    let _ = Command::new("kill").args(["-TERM", &pid.to_string()]).output();
+}
+
+/// The port from `start-server <port>`, or `default` when none is given
+fn server_port(command: &str, default: u16) -> u16 {
+    command
+        .split_whitespace()
+        .nth(1)
+        .and_then(|p| p.parse::<u16>().ok())
+        .unwrap_or(default)
+}
+
+/// The first port from `start` upwards that can be bound on `bind`
+fn find_free_port(bind: &str, start: u16) -> Result<u16, CodexError> {
+    for port in (start..=u16::MAX).take(100) {
+        match TcpListener::bind((bind, port)) {
+            Ok(_) => return Ok(port),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(format!("Cannot listen on {}: {}", bind, e).into()),
+        }
+    }
+    Err(format!("No free port found from {} upwards", start).into())
+}
+
+/// Static file servers to try in order, as (program, args)
+fn static_server_commands(port: u16, bind: Option<&str>) -> Vec<(&'static str, Vec<String>)> {
+    let port = port.to_string();
+    let mut python = vec!["-m".to_string(), "http.server".to_string(), port.clone()];
+    if let Some(addr) = bind {
+        python.extend(["--bind".to_string(), addr.to_string()]);
+    }
+    let serve_listen = match bind {
+        Some(addr) => format!("tcp://{}:{}", addr, port),
+        None => port.clone(),
+    };
+    let mut http_server = vec!["--yes".to_string(), "http-server".to_string(), "-p".to_string(), port];
+    if let Some(addr) = bind {
+        http_server.extend(["-a".to_string(), addr.to_string()]);
+    }
+
+    vec![
+        ("python", python.clone()),
+        ("python3", python),
+        ("npx", vec!["--yes".to_string(), "serve".to_string(), "-l".to_string(), serve_listen]),
+        ("npx", http_server),
+    ]
+}
+
+/// Start the first static file server that comes up. One that exits within
+/// a moment of starting (e.g. it couldn't bind) counts as failed.
+fn spawn_static_server(port: u16, bind: Option<&str>, dir: &Path) -> Result<(String, Child), CodexError> {
+    for (program, args) in static_server_commands(port, bind) {
+        debug!("trying static server {} {:?}", program, args);
+        let name = if program == "npx" {
+            format!("npx {}", args[1])
+        } else {
+            format!("{} -m http.server", program)
+        };
+        let mut child = match Command::new(program)
+            .args(&args)
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match child.wait_timeout(Duration::from_millis(500)) {
+            Ok(None) => return Ok((name, child)),
+            _ => show_warning(&format!("{} did not start, trying the next server", name)),
+        }
+    }
+    Err("No static file server available. Install Python 3 (python -m http.server) or Node.js (npx serve).".into())
+}
+
+fn start_local_server(port: u16, bind: Option<&str>, workspace: &Workspace) -> Result<(String, ServerProcess), CodexError> {
+    println!("{}", style("Starting local server...").bold().yellow());
+
+    let port = find_free_port(bind.unwrap_or("0.0.0.0"), port).inspect(|free| {
+            if *free != port {
+                show_warning(&format!("Port {} is in use, using {} instead", port, free));
+            }
+        })?;
+
+    let (name, child) = spawn_static_server(port, bind, workspace.path())?;
+    let server = ServerProcess::new(&name, child).with_port(port);
+
+    Ok((
+        format!(
+            "Local server ({}, pid {}) started on http://{}:{} serving {}. Use !stop to stop it.",
+            name,
+            server.pid(),
+            bind.unwrap_or("localhost"),
+            port,
+            workspace.path().display()
+        ),
+        server,
+    ))
+}
+
+/// Where generated code, environments (venv, node_modules) and servers
+/// live: `--workdir` or the current directory, resolved to an absolute path
+/// once at startup so every command agrees on it
+#[derive(Debug, Clone)]
+struct Workspace {
+    root: PathBuf,
+    /// Set for the fallback, which is removed once the session is done
+    /// with it
+    scratch: Option<Arc<tempfile::TempDir>>,
+}
+
+impl Workspace {
+    /// The workdir, or the current directory without one. The filesystem
+    /// root or the home directory is refused unless `allow_unsafe`, since
+    /// scratch files, venvs and servers would land there.
+    fn resolve(workdir: Option<&str>, allow_unsafe: bool) -> Result<Self, CodexError> {
+        let home = dirs::home_dir().and_then(|home| fs::canonicalize(home).ok());
+        Self::resolve_from(workdir, &env::current_dir()?, home.as_deref(), allow_unsafe)
+    }
+
+    fn resolve_from(workdir: Option<&str>, cwd: &Path, home: Option<&Path>, allow_unsafe: bool) -> Result<Self, CodexError> {
+        let root = match workdir {
+            Some(dir) => {
+                fs::create_dir_all(dir).map_err(|e| format!("Cannot create workdir {}: {}", dir, e))?;
+                fs::canonicalize(dir).map_err(|e| format!("Cannot resolve workdir {}: {}", dir, e))?
+            }
+            None => cwd.to_path_buf(),
+        };
+
+        let unsafe_kind = if root.parent().is_none() {
+            Some("the filesystem root")
+        } else if home == Some(root.as_path()) {
+            Some("your home directory")
+        } else {
+            None
+        };
+        match unsafe_kind {
+            Some(kind) if !allow_unsafe => Err(format!(
+                "Refusing to use {} ({}) as the workdir. Pass a different --workdir, or --allow-unsafe-workdir if you mean it.",
+                kind,
+                root.display()
+            )
+            .into()),
+            _ => Ok(Workspace { root, scratch: None }),
+        }
+    }
+
+    /// A new private directory in the system temp dir, for when the workdir
+    /// can't be written to. Not a fixed path, which another user could
+    /// create first or plant files in.
+    fn fallback() -> Result<Self, CodexError> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("codexcli-");
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o700));
+        let scratch = builder
+            .tempdir()
+            .map_err(|e| format!("Cannot create a temp dir in {}: {}", env::temp_dir().display(), e))?;
+        Ok(Workspace { root: scratch.path().to_path_buf(), scratch: Some(Arc::new(scratch)) })
+    }
+
+    fn check_writable(&self) -> Result<(), CodexError> {
+        tempfile::tempfile_in(&self.root).map(|_| ()).map_err(|e| {
+            let reason = match e.kind() {
+                io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+                io::ErrorKind::ReadOnlyFilesystem => "read-only file system".to_string(),
+                _ => e.to_string(),
+            };
+            format!("Cannot write temp files in {}: {}", self.root.display(), reason).into()
+        })
+    }
+
+    fn path(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// The sources of the code cells in `.ipynb` JSON, in order. None if it
+/// isn't a notebook.
+fn notebook_cells(code: &str) -> Option<Vec<String>> {
+    let notebook: serde_json::Value = serde_json::from_str(code).ok()?;
+    let cells = notebook.get("cells")?.as_array()?;
+    Some(
+        cells
+            .iter()
+            .filter(|cell| cell["cell_type"] == "code")
+            .map(|cell| match &cell["source"] {
+                // Sources are usually a list of lines that keep their `\n`
+                serde_json::Value::Array(lines) => lines.iter().filter_map(|line| line.as_str()).collect(),
+                source => source.as_str().unwrap_or_default().to_string(),
+            })
+            .filter(|source| !source.trim().is_empty())
+            .collect(),
+    )
+}
+
+/// A Python script running `cells` one after another in a shared namespace,
+/// with a header before each cell's output. Jupyter's `%magic` and `!shell`
+/// lines aren't Python, so they're commented out, and the first failing
+/// cell stops the rest.
+fn notebook_script(cells: &[String]) -> String {
+    let cells: Vec<String> = cells
+        .iter()
+        .map(|source| {
+            source
+                .lines()
+                .map(|line| {
+                    if line.trim_start().starts_with(['%', '!']) {
+                        format!("# {}", line)
+                    } else {
+                        line.to_string()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .collect();
+    // A JSON list of strings is also a Python one
+    let cells = serde_json::to_string(&cells).unwrap_or_else(|_| "[]".to_string());
+    format!(
+        r#"import traceback
+
+cells = {}
+namespace = {{"__name__": "__main__"}}
+for number, source in enumerate(cells, 1):
+    print(f"── Cell {{number}} ──", flush=True)
+    try:
+        exec(compile(source, f"<cell {{number}}>", "exec"), namespace)
+    except Exception:
+        traceback.print_exc()
+        raise SystemExit(1)
+"#,
+        cells
+    )
+}
+
+/// `text` cut down to a safe file name: letters, digits, `-`, `_` and `.`,
+/// with anything else collapsed into single dashes
+fn sanitize_file_name(text: &str) -> String {
+    let mut name = String::new();
+    for c in text.trim().chars() {
+        if c.is_ascii_alphanumeric() || matches!(c, '_' | '.') {
+            name.push(c);
+        } else if !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.trim_matches(['-', '.']).chars().take(60).collect()
+}
+
+/// The name an HTML block is saved under in `dir`: its `<!-- file: ... -->`
+/// hint, else its `<title>`, else a timestamp. A file that's already there
+/// and isn't one of `ours` gets a numbered name instead of being replaced.
+fn html_file_name(code: &str, dir: &Path, ours: &[PathBuf]) -> String {
+    let lower = code.to_lowercase();
+    let title = lower
+        .find("<title>")
+        .map(|start| start + "<title>".len())
+        .and_then(|start| lower[start..].find("</title>").and_then(|end| code.get(start..start + end)));
+    let stem = filename_hint(code)
+        .and_then(|hint| Path::new(&hint).file_name().map(|name| name.to_string_lossy().into_owned()))
+        .map(|name| sanitize_file_name(name.trim_end_matches(".html").trim_end_matches(".htm")))
+        .or_else(|| title.map(|title| sanitize_file_name(&title.to_lowercase())))
+        .filter(|stem| !stem.is_empty())
+        .unwrap_or_else(|| Local::now().format("page-%Y%m%d-%H%M%S-%3f").to_string());
+
+    let mut name = format!("{}.html", stem);
+    let mut n = 2;
+    while dir.join(&name).exists() && !ours.contains(&dir.join(&name)) {
+        name = format!("{}-{}.html", stem, n);
+        n += 1;
+    }
+    name
+}
+
+/// A language code blocks can be run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lang {
+    Python,
+    JavaScript,
+    TypeScript,
+    Rust,
+    C,
+    Cpp,
+    Shell,
+    Go,
+    Ruby,
+    Php,
+    Html,
+    Sql,
+    Notebook,
+}
+
+/// The language a code fence tag (or file extension) names, accepting the
+/// usual aliases, or None if we can't run it
+fn normalize_language(name: &str) -> Option<Lang> {
+    Some(match name.trim().to_lowercase().as_str() {
+        "python" | "py" | "python3" | "py3" => Lang::Python,
+        "javascript" | "js" | "node" | "nodejs" | "mjs" => Lang::JavaScript,
+        "typescript" | "ts" => Lang::TypeScript,
+        "rust" | "rs" => Lang::Rust,
+        "c" | "h" => Lang::C,
+        "cpp" | "c++" | "cxx" | "cc" | "hpp" => Lang::Cpp,
+        "bash" | "sh" | "shell" | "zsh" => Lang::Shell,
+        "go" | "golang" => Lang::Go,
+        "ruby" | "rb" => Lang::Ruby,
+        "php" => Lang::Php,
+        "html" | "htm" | "xhtml" => Lang::Html,
+        "sql" | "sqlite" | "sqlite3" => Lang::Sql,
+        "ipynb" | "notebook" | "jupyter" => Lang::Notebook,
+        _ => return None,
+    })
+}
+
+impl Lang {
+    /// The name shown for the language, and used to tag its blocks
+    fn name(self) -> &'static str {
+        match self {
+            Lang::Python => "python",
+            Lang::JavaScript => "javascript",
+            Lang::TypeScript => "typescript",
+            Lang::Rust => "rust",
+            Lang::C => "c",
+            Lang::Cpp => "cpp",
+            Lang::Shell => "bash",
+            Lang::Go => "go",
+            Lang::Ruby => "ruby",
+            Lang::Php => "php",
+            Lang::Html => "html",
+            Lang::Sql => "sql",
+            Lang::Notebook => "ipynb",
+        }
+    }
+
+    /// Source file extension
+    fn extension(self) -> &'static str {
+        match self {
+            Lang::Python => "py",
+            Lang::JavaScript => "js",
+            Lang::TypeScript => "ts",
+            Lang::Rust => "rs",
+            Lang::C => "c",
+            Lang::Cpp => "cpp",
+            Lang::Shell => "sh",
+            Lang::Go => "go",
+            Lang::Ruby => "rb",
+            Lang::Php => "php",
+            Lang::Html => "html",
+            Lang::Sql => "sql",
+            Lang::Notebook => "ipynb",
+        }
+    }
+}
+
+/// The language `language` names, or an "Unsupported language" error
+fn supported_language(language: &str) -> Result<Lang, CodexError> {
+    normalize_language(language).ok_or_else(|| format!("Unsupported language: {}", language).into())
+}
+
+/// The command lines behind `create-react-app`, `npm start` and
+/// `start-server`, or None for anything else
+fn special_command_plan(command: &str, config: &ResolvedConfig) -> Option<Vec<String>> {
+    let command = command.trim();
+    if command == "create-react-app" {
+        Some(vec!["npx create-react-app react-app".to_string()])
+    } else if command == "npm start" {
+        Some(vec!["npm start   (in react-app/)".to_string()])
+    } else if command.starts_with("start-server") {
+        let port = server_port(command, config.port);
+        let bind = config.bind.as_ref().map(|addr| format!(" --bind {}", addr)).unwrap_or_default();
+        Some(vec![format!("python -m http.server {}{}   (or the next free port)", port, bind)])
+    } else {
+        None
+    }
+}
+
+/// Whether a page links to files next to it (`src="app.js"`,
+/// `href="style.css"`), which browsers won't load from a file:// URL
+fn references_local_files(html: &str) -> bool {
+    const REMOTE: &[&str] = &["http:", "https:", "//", "data:", "#", "mailto:", "javascript:"];
+    let lower = html.to_lowercase();
+    ["src=", "href="].iter().any(|attr| {
+        lower.match_indices(attr).any(|(i, _)| {
+            let rest = &lower[i + attr.len()..];
+            let rest = rest.strip_prefix(['"', '\'']).unwrap_or(rest);
+            let value = rest.split(['"', '\'', ' ', '>']).next().unwrap_or("");
+            !value.is_empty() && !REMOTE.iter().any(|prefix| value.starts_with(prefix))
+        })
+    })
+}
+
+/// Open a file or URL with the platform's default handler
+fn open_in_browser(target: &str) -> Result<(), CodexError> {
+    let status = if cfg!(windows) {
+        Command::new("cmd").args(["/C", "start", ""]).arg(target).status()?
+    } else if cfg!(target_os = "macos") {
+        Command::new("open").arg(target).status()?
+    } else {
+        Command::new("xdg-open").arg(target).status()?
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err("Failed to open HTML file in browser".into())
+    }
+}
+
+/// Open the `--db` database, or an in-memory one when none was given
+fn open_database(path: Option<&str>) -> Result<Connection, CodexError> {
+    match path {
+        Some(path) => Connection::open(path).map_err(|e| format!("Could not open database {}: {}", path, e).into()),
+        None => Connection::open_in_memory().map_err(|e| format!("Could not open in-memory database: {}", e).into()),
+    }
+}
+
+/// A result cell as shown in the table. `|` would end the cell early.
+fn sql_cell(value: ValueRef) -> String {
+    match value {
+        ValueRef::Null => "NULL".to_string(),
+        ValueRef::Integer(n) => n.to_string(),
+        ValueRef::Real(x) => x.to_string(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).replace('|', "¦").replace('\n', " "),
+        ValueRef::Blob(bytes) => format!("<{} bytes>", bytes.len()),
+    }
+}
+
+/// Run each statement in `sql` in turn. Queries print their rows as a
+/// table; anything else prints how many rows it changed.
+fn run_sql(sql: &str, conn: &Connection, capture: bool) -> Result<String, CodexError> {
+    let sql_error = |e: rusqlite::Error| CodexError::Runtime(format!("SQL error: {}", e));
+    let mut output = String::new();
+    let mut batch = Batch::new(conn, sql);
+
+    while let Some(mut stmt) = batch.next().map_err(sql_error)? {
+        let text = if stmt.column_count() > 0 {
+            let columns = stmt.column_count();
+            let mut table = format!("| {} |\n|{}\n", stmt.column_names().join(" | "), "---|".repeat(columns));
+            let mut count = 0;
+            let mut rows = stmt.query([]).map_err(sql_error)?;
+            while let Some(row) = rows.next().map_err(sql_error)? {
+                let cells = (0..columns)
+                    .map(|i| row.get_ref(i).map(sql_cell))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(sql_error)?;
+                table.push_str(&format!("| {} |\n", cells.join(" | ")));
+                count += 1;
+            }
+            format!("{}({} row{})\n", table, count, if count == 1 { "" } else { "s" })
+        } else {
+            let is_dml = stmt.expanded_sql().is_some_and(|text| {
+                let keyword = text.split_whitespace().next().unwrap_or("").to_uppercase();
+                matches!(keyword.as_str(), "INSERT" | "UPDATE" | "DELETE" | "REPLACE")
+            });
+            let before = conn.total_changes();
+            stmt.execute([]).map_err(sql_error)?;
+            if is_dml {
+                let changed = conn.total_changes() - before;
+                format!("{} row{} affected\n", changed, if changed == 1 { "" } else { "s" })
+            } else {
+                "OK\n".to_string()
+            }
+        };
+        print!("{}", format_response(&text));
+        output.push_str(&text);
+    }
+
+    Ok(if capture { output } else { String::new() })
+}
+
+/// A way to run shell blocks on Windows, which has no bash of its own
+enum WindowsShell {
+    Wsl,
+    /// A bash.exe, usually Git Bash
+    Bash(PathBuf),
+    /// pwsh or powershell, for simple scripts whose commands it shares
+    PowerShell(String),
+}
+
+/// Git for Windows' bash.exe. The bash.exe in System32 is WSL's launcher,
+/// so look next to git rather than on PATH.
+fn git_bash() -> Option<PathBuf> {
+    let beside_git = which::which("git")
+        .ok()
+        .and_then(|git| git.parent()?.parent().map(|root| root.join("bin").join("bash.exe")));
+    beside_git
+        .into_iter()
+        .chain([
+            PathBuf::from(r"C:\Program Files\Git\bin\bash.exe"),
+            PathBuf::from(r"C:\Program Files (x86)\Git\bin\bash.exe"),
+        ])
+        .find(|path| path.is_file())
+}
+
+/// A `--shell` that can run scripts; cmd, the Windows default, can't
+fn script_shell_override(shell: &str) -> Option<WindowsShell> {
+    let name = Path::new(shell).file_stem()?.to_string_lossy().to_lowercase();
+    match name.as_str() {
+        "wsl" => Some(WindowsShell::Wsl),
+        "bash" | "sh" => Some(WindowsShell::Bash(PathBuf::from(shell))),
+        "pwsh" | "powershell" => Some(WindowsShell::PowerShell(shell.to_string())),
+        _ => None,
+    }
+}
+
+/// The first of WSL, Git Bash and PowerShell that is installed
+fn detect_script_shell() -> Option<WindowsShell> {
+    if on_path("wsl") {
+        return Some(WindowsShell::Wsl);
+    }
+    if let Some(bash) = git_bash() {
+        return Some(WindowsShell::Bash(bash));
+    }
+    ["pwsh", "powershell"]
+        .into_iter()
+        .find(|program| on_path(program))
+        .map(|program| WindowsShell::PowerShell(program.to_string()))
+}
+
+/// The command that runs the shell block at `file` on Windows: the
+/// `--shell` if it can run scripts, else the first installed of WSL, Git
+/// Bash and PowerShell. Falling back from WSL asks first, since the others
+/// behave differently.
+fn windows_script_command(file: &Path, base: &Path, shell: Option<&str>) -> Result<Command, CodexError> {
+    let (chosen, explicit) = match shell.and_then(script_shell_override) {
+        Some(chosen) => (chosen, true),
+        None => {
+            let chosen = detect_script_shell().ok_or_else(|| CodexError::InterpreterNotFound {
+                name: "bash".to_string(),
+                hint: "install WSL with `wsl --install` or Git for Windows, or pass --shell".to_string(),
+            })?;
+            (chosen, false)
+        }
+    };
+
+    let mut cmd = match chosen {
+        WindowsShell::Wsl => {
+            let mut c = Command::new("wsl");
+            // WSL can't take a Windows path, but a relative one works
+            let relative = file.strip_prefix(base).unwrap_or(file);
+            c.args(["bash", relative.to_string_lossy().replace('\\', "/").as_str()]);
+            c
+        }
+        WindowsShell::Bash(bash) => {
+            if !explicit && !confirm(&format!("WSL isn't available. Run the script with {}?", bash.display())) {
+                return Err("Shell block not run".into());
+            }
+            let mut c = Command::new(bash);
+            c.arg(file);
+            c
+        }
+        WindowsShell::PowerShell(program) => {
+            if !explicit
+                && !confirm(&format!(
+                    "Neither WSL nor Git Bash is available. Try the script in {}? Only simple commands work the same.",
+                    program
+                ))
+            {
+                return Err("Shell block not run".into());
+            }
+            // PowerShell only runs files ending in .ps1
+            let script = file.with_extension("ps1");
+            fs::copy(file, &script)?;
+            let mut c = Command::new(program);
+            c.args(["-NoProfile", "-ExecutionPolicy", "Bypass", "-File"]).arg(script);
+            c
+        }
+    };
+    cmd.current_dir(base);
+    Ok(cmd)
+}
+
+/// What `execute_code_block` would do for this block, without doing it
+fn dry_run_code_block(
+    code: &str,
+    language: &str,
+    filename: Option<&str>,
+    workspace: &Workspace,
+    config: &ResolvedConfig,
+) -> Result<String, CodexError> {
+    let mut plan = format!("(dry run) nothing was executed\nWorking directory: {}\n", workspace.path().display());
+
+    if let Some(commands) = special_command_plan(code, config) {
+        for command in commands {
+            plan.push_str(&format!("Would run: {}\n", command));
+        }
+        return Ok(plan);
+    }
+
+    let lang = supported_language(language)?;
+    let file = if let Some(name) = filename.filter(|name| stays_inside(name)) {
+        name.to_string()
+    } else if lang == Lang::Html {
+        html_file_name(code, workspace.path(), &[])
+    } else {
+        format!("temp_code.{}", lang.extension())
+    };
+    let compiled = |compiler: &str| vec![format!("{} {} -o temp_code", compiler, file), "./temp_code".to_string()];
+    let commands = match lang {
+        Lang::Python | Lang::Notebook => vec![format!("{} {}", venv_python(workspace.path()).display(), file)],
+        Lang::JavaScript => vec![format!("node {}", file)],
+        Lang::TypeScript => vec![format!("npx ts-node {}", file)],
+        Lang::Rust => compiled("rustc"),
+        Lang::C => compiled("cc"),
+        Lang::Cpp => compiled("c++"),
+        Lang::Go => vec![format!("go run {}", file)],
+        Lang::Shell => vec![format!("bash {}", file)],
+        Lang::Ruby => vec![format!("ruby {}", file)],
+        Lang::Php => vec![format!("php {}", file)],
+        Lang::Sql => vec![format!("execute against {}", config.db.as_deref().unwrap_or("an in-memory SQLite database"))],
+        Lang::Html => vec![format!("open {} in the default browser", file)],
+    };
+    for command in commands {
+        plan.push_str(&format!("Would run: {}\n", command));
+    }
+    plan.push_str(&format!("--- {} ---\n{}", file, code));
+    Ok(plan)
+}
+
+/// Run one code block. A block with a `filename` (from a ```lang:path
+/// fence) is written to that path in the workdir and kept, so a later block
+/// can use it; others go to a scratch file that's removed afterwards.
+fn execute_code_block(
+    code: &str,
+    language: &str,
+    filename: Option<&str>,
+    session: &mut Session,
+    config: &ResolvedConfig,
+) -> Result<String, CodexError> {
+    // A notebook runs as one Python script, so later cells see what earlier
+    // ones defined
+    let notebook = normalize_language(language) == Some(Lang::Notebook);
+    match notebook_cells(code) {
+        Some(cells) if notebook || language.eq_ignore_ascii_case("json") => {
+            println!("{}", style(format!("Notebook with {} code cell(s)", cells.len())).dim());
+            return execute_code_block(&notebook_script(&cells), "python", None, session, config);
+        }
+        None if notebook => return Err("The block is not valid notebook JSON".into()),
+        _ => {}
+    }
+
+    if config.dry_run {
+        return dry_run_code_block(code, language, filename, &session.workspace, config);
+    }
+
+    // Check for special commands
+    if code.trim() == "create-react-app" {
+        return setup_react_environment(&session.workspace)
+            .map(|_| "React application created successfully. Use 'npm start' to run the development server.".to_string());
+    }
+    
+    if code.trim() == "npm start" {
+        let (msg, server) = start_react_server(&session.workspace)?;
+        session.servers.push(server);
+        return Ok(msg);
+    }
     
-    if let Some(dir) = workdir {
-        cmd.current_dir(Path::new(dir).join("react-app"));
+    if code.trim().starts_with("start-server") {
+        let port = server_port(code, config.port);
+        let (msg, server) = start_local_server(port, config.bind.as_deref(), &session.workspace)?;
+        session.servers.push(server);
+        return Ok(msg);
+    }
+
+    // A read-only workdir (running from a system directory, say) would fail
+    // every block, so move to the temp dir for the rest of the session
+    if let Err(e) = session.workspace.check_writable() {
+        let fallback = Workspace::fallback()?;
+        show_warning(&format!(
+            "{}. Running code in {} instead; try --workdir to a writable location.",
+            e,
+            fallback.path().display()
+        ));
+        session.workspace = fallback;
+    }
+    let workspace = &session.workspace.clone();
+
+    let lang = supported_language(language)?;
+
+    // SQL runs in-process, against a connection kept for the whole session
+    // so tables created by one block are there for the next
+    if lang == Lang::Sql {
+        let conn = match &mut session.db {
+            Some(conn) => conn,
+            db => db.insert(open_database(config.db.as_deref())?),
+        };
+        return run_sql(code, conn, config.capture);
+    }
+
+    let base = workspace.path();
+    let timeout = config.timeout;
+    let capture = config.capture;
+
+    // Each run gets its own scratch directory for the source and any build
+    // output; dropping it removes everything, even on the error path.
+    // Programs still run with `base` as their cwd so files they create stay.
+    let scratch = tempfile::Builder::new()
+        .prefix(".codexcli-")
+        .tempdir_in(base)
+        .map_err(|e| format!("Cannot write temp file in {}: {}", base.display(), e))?;
+    let named = match filename {
+        Some(name) if stays_inside(name) => Some(base.join(name)),
+        Some(name) => {
+            show_warning(&format!("Ignoring file name {} outside {}", name, base.display()));
+            None
+        }
+        None => None,
+    };
+    let fpath = if let Some(path) = named {
+        let unchanged = fs::read_to_string(&path).is_ok_and(|old| old == code);
+        if path.exists() && !unchanged && !confirm(&format!("{} exists. Overwrite it?", path.display())) {
+            return Err(format!("Not overwriting {}", path.display()).into());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        path
+    } else if lang == Lang::Html {
+        // The browser may still be reading the page after we return
+        let path = base.join(html_file_name(code, base, &session.html_files));
+        if !session.html_files.contains(&path) {
+            session.html_files.push(path.clone());
+            if !session.keep_temp {
+                if let Ok(mut cleanup) = EXIT_CLEANUP.lock() {
+                    cleanup.pages.push(path.clone());
+                }
+            }
+        }
+        path
+    } else {
+        scratch.path().join(format!("temp_code.{}", lang.extension()))
+    };
+    // How the page is reached over http: its path under the workdir
+    let fname = fpath
+        .strip_prefix(base)
+        .unwrap_or(&fpath)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    File::create(&fpath)
+        .and_then(|mut f| f.write_all(code.as_bytes()))
+        .map_err(|e| format!("Cannot write temp file {}: {}", fpath.display(), e))?;
+    debug!("running {} block from {} in {}", language, fpath.display(), base.display());
+    trace!("block source:\n{}", code);
+
+    let result = || -> Result<String, CodexError> {
+        match lang {
+            Lang::Python => {
+                // Setup Python environment
+                setup_python_environment(base)?;
+                
+                let python_path = venv_python(base);
+
+                // First try non-interactive mode
+                let mut out = run_python_file(&python_path, &fpath, base, timeout)?;
+
+                // Handle missing modules
+                if !out.status.success() && String::from_utf8_lossy(&out.stderr).contains("ModuleNotFoundError") {
+                    out = handle_python_error(out, &fpath, workspace, timeout, config.autoinstall)?;
+                }
+
+                // Stdout was already streamed to the terminal
+                if out.status.success() {
+                    return Ok(if capture { String::from_utf8_lossy(&out.stdout).to_string() } else { String::new() });
+                }
+
+                let err = String::from_utf8_lossy(&out.stderr).to_string();
+
+                // For any input-related errors, switch to interactive mode
+                if err.contains("input(") || err.contains("EOF") || err.contains("EOFError") {
+                    println!(
+                        "{}",
+                        style("\nSwitching to interactive mode. Press Ctrl+C when done.").bold().yellow()
+                    );
+                    
+                    let mut child = Command::new(&python_path)
+                        .arg(&fpath)
+                        .current_dir(base)
+                        .stdin(Stdio::inherit())
+                        .stdout(Stdio::inherit())
+                        .stderr(Stdio::inherit())
+                        .spawn()
+                        ?;
+
+                    let status = wait_with_timeout(&mut child, None)?;
+                    if status.success() {
+                        Ok(String::new())
+                    } else {
+                        Err(CodexError::Runtime(format!("Python exited with status: {}", status)))
+                    }
+                } else {
+                    Err(CodexError::Runtime(err))
+                }
+            }
+            Lang::JavaScript => {
+                // Setup Node.js environment
+                setup_node_environment(base)?;
+                
+                let child = spawn_program(Command::new("node").arg(&fpath).current_dir(base), capture)
+                    ?;
+                let out = finish_program(child, timeout, capture)?;
+
+                // Missing modules can only be spotted when stderr was captured
+                let stderr = String::from_utf8_lossy(&out.stderr).to_string();
+                if !out.status.success() && stderr.contains("Cannot find module") {
+                    handle_node_error(&stderr, code, filename, session, config)
+                } else {
+                    program_result(out, "Node.js")
+                }
+            }
+            Lang::TypeScript => {
+                setup_node_environment(base)?;
+                install_node_package("typescript", base)?;
+                install_node_package("ts-node", base)?;
+                
+                let child = spawn_program(Command::new("npx").arg("ts-node").arg(&fpath).current_dir(base), capture)
+                    ?;
+                program_result(finish_program(child, timeout, capture)?, "TypeScript")
+            }
+            Lang::Rust => {
+                let binary = scratch.path().join(if cfg!(windows) { "temp_code.exe" } else { "temp_code" });
+                let child = Command::new("rustc")
+                    .arg(&fpath)
+                    .arg("-o")
+                    .arg(&binary)
+                    .current_dir(base)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    ?;
+                let out = output_with_timeout(child, timeout)?;
+                
+                if !out.status.success() {
+                    return Err(CodexError::CompileFailed {
+                        compiler: "rustc".to_string(),
+                        output: String::from_utf8_lossy(&out.stderr).trim_end().to_string(),
+                    });
+                }
+
+                let child = spawn_program(Command::new(&binary).current_dir(base), capture)
+                    ?;
+                program_result(finish_program(child, timeout, capture)?, "Rust program")
+            }
+            Lang::C | Lang::Cpp => {
+                let binary = scratch.path().join(if cfg!(windows) { "temp_code.exe" } else { "temp_code" });
+                let compilers: &[&str] = if lang == Lang::C { &["cc", "gcc", "clang"] } else { &["c++", "g++", "clang++"] };
+                compile_c(compilers, &fpath, &binary, base, timeout)?;
+
+                let child = spawn_program(Command::new(&binary).current_dir(base), capture)
+                    ?;
+                program_result(finish_program(child, timeout, capture)?, "Program")
+            }
+            Lang::Go => {
+                let child = spawn_program(Command::new("go").arg("run").arg(&fpath).current_dir(base), capture)
+                    .map_err(|e| spawn_error(e, "Go", "https://go.dev/dl/"))?;
+                program_result(finish_program(child, timeout, capture)?, "Go program")
+            }
+            Lang::Ruby => {
+                let child = spawn_program(Command::new("ruby").arg(&fpath).current_dir(base), capture)
+                    .map_err(|e| spawn_error(e, "Ruby", "https://www.ruby-lang.org/en/downloads/"))?;
+                program_result(finish_program(child, timeout, capture)?, "Ruby script")
+            }
+            Lang::Php => {
+                let child = spawn_program(Command::new("php").arg(&fpath).current_dir(base), capture)
+                    .map_err(|e| spawn_error(e, "PHP", "https://www.php.net/downloads"))?;
+                program_result(finish_program(child, timeout, capture)?, "PHP script")
+            }
+            Lang::Shell => {
+                let mut cmd = if cfg!(windows) {
+                    windows_script_command(&fpath, base, config.shell.as_deref())?
+                } else {
+                    let mut c = Command::new("bash");
+                    c.arg(&fpath);
+                    c
+                };
+
+                let child = spawn_program(cmd.current_dir(base), capture)?;
+                program_result(finish_program(child, timeout, capture)?, "Bash script")
+            }
+            Lang::Html => {
+                // A file:// page usually can't load the files it links to,
+                // so serve the workspace over http instead
+                let running = session.servers.iter().find_map(|server| server.port);
+                let serve = references_local_files(code)
+                    && (running.is_some()
+                        || confirm("This page loads files relative to it. Serve the workdir and open it over http?"));
+                let target = if serve {
+                    let port = match running {
+                        Some(port) => port,
+                        None => {
+                            let (msg, server) = start_local_server(config.port, config.bind.as_deref(), workspace)?;
+                            println!("{}", style(msg).dim());
+                            let port = server.port.unwrap_or(config.port);
+                            session.servers.push(server);
+                            port
+                        }
+                    };
+                    format!("http://localhost:{}/{}", port, fname)
+                } else {
+                    fpath.display().to_string()
+                };
+
+                println!("{}", style("Opening HTML in default browser...").bold().yellow());
+                open_in_browser(&target)?;
+                Ok(format!("HTML opened in browser: {}", target))
+            }
+            // Both are run before this point
+            Lang::Sql | Lang::Notebook => Err(format!("Unsupported language: {}", language).into()),
+        }
+    }();
+
+    // Clean up the source and any build output, unless asked to keep them
+    if config.keep_temp {
+        let kept = scratch.keep();
+        println!("{} {}", style("Kept temp files in").dim(), style(kept.display()).white());
+    } else {
+        drop(scratch);
+    }
+
+    result
+}
+
+/// One row of `ollama list`
+struct ModelInfo {
+    name: String,
+    size: String,
+    modified: String,
+}
+
+/// Parse `ollama list` output. Columns are separated by runs of spaces, and
+/// the size ("2.0 GB") and modified ("3 weeks ago") values contain single
+/// spaces, so split on two or more.
+fn parse_model_list(output: &str) -> Vec<ModelInfo> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut cols = line.split("  ").map(str::trim).filter(|c| !c.is_empty());
+            let name = cols.next()?.to_string();
+            let _id = cols.next();
+            Some(ModelInfo {
+                name,
+                size: cols.next().unwrap_or("").to_string(),
+                modified: cols.next().unwrap_or("").to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Sizes as `ollama list` shows them, e.g. "2.0 GB" or "274 MB"
+fn format_size(bytes: u64) -> String {
+    let bytes = bytes as f64;
+    if bytes >= 1e9 {
+        format!("{:.1} GB", bytes / 1e9)
+    } else if bytes >= 1e6 {
+        format!("{:.0} MB", bytes / 1e6)
+    } else if bytes >= 1e3 {
+        format!("{:.0} KB", bytes / 1e3)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+/// The models in an `/api/tags` reply, as `ollama list` rows
+fn parse_model_tags(body: &serde_json::Value) -> Vec<ModelInfo> {
+    body["models"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|model| {
+            Some(ModelInfo {
+                name: model["name"].as_str()?.to_string(),
+                size: model["size"].as_u64().map(format_size).unwrap_or_default(),
+                modified: model["modified_at"]
+                    .as_str()
+                    .and_then(|at| DateTime::parse_from_rfc3339(at).ok())
+                    .map(|at| at.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// The models the server has, from `ollama list` or the API's `/api/tags`
+fn installed_models(backend: Backend) -> Result<Vec<ModelInfo>, CodexError> {
+    if backend == Backend::Http {
+        let url = format!("{}/api/tags", ollama_url());
+        debug!("GET {}", url);
+        let reply = http_client().get(&url).send().map_err(http_error)?;
+        if !reply.status().is_success() {
+            return Err(format!("Ollama returned {} for {}", reply.status(), url).into());
+        }
+        let body: serde_json::Value = reply.json().map_err(http_error)?;
+        return Ok(parse_model_tags(&body));
+    }
+    debug!("ollama list");
+    let output = cmd!("ollama", "list")
+        .read()
+        .map_err(|e| spawn_error(e, "Ollama", ollama_install_hint()))?;
+    Ok(parse_model_list(&output))
+}
+
+fn list_installed_models(backend: Backend) -> Result<Vec<String>, CodexError> {
+    Ok(installed_models(backend)?.into_iter().map(|m| m.name).collect())
+}
+
+/// Make sure `model` is available, offering to pull it if not
+fn ensure_model_installed(model: &str, backend: Backend) -> Result<(), CodexError> {
+    let installed = list_installed_models(backend)?;
+    if model_is_installed(model, &installed) {
+        return Ok(());
+    }
+    if !confirm(&format!("Model '{}' is not installed. Pull it now?", model)) {
+        return Err(format!("Model '{}' is not installed", model).into());
+    }
+
+    pull_model(model, backend)
+}
+
+/// Percent complete and a short status from one line of `ollama pull`
+/// output, e.g. `pulling 6a0746a1ec1a: 45% ▕███   ▏ 2.1 GB/4.7 GB  30 MB/s  1m20s`
+fn parse_pull_line(line: &str) -> Option<(u64, String)> {
+    let (label, rest) = line.split_once(':')?;
+    let percent = rest
+        .split_whitespace()
+        .find_map(|word| word.strip_suffix('%')?.parse::<u64>().ok())?;
+    let detail = rest.rsplit_once('▏').map(|(_, detail)| detail.trim()).unwrap_or_default();
+    Some((percent.min(100), format!("{} {}", label.trim(), detail).trim_end().to_string()))
+}
+
+/// Pull `model` with `ollama pull` or the API's `/api/pull`, drawing its
+/// progress as a bar
+fn pull_model(model: &str, backend: Backend) -> Result<(), CodexError> {
+    let bar = ProgressBar::new(100);
+    bar.set_style(progress_style());
+    bar.set_message(format!("pulling {}", model));
+    if appearance().animate {
+        bar.enable_steady_tick(Duration::from_millis(80));
+    }
+
+    let result = match backend {
+        Backend::Http => pull_model_http(model, &bar),
+        Backend::Cli => pull_model_cli(model, &bar),
+    };
+    match result {
+        Ok(()) => {
+            bar.finish_with_message(format!("pulled {}", model));
+            Ok(())
+        }
+        Err(e) => {
+            bar.abandon();
+            Err(e)
+        }
+    }
+}
+
+/// Run `ollama pull`. Lines that aren't progress ("verifying sha256
+/// digest", errors) are printed as they come.
+fn pull_model_cli(model: &str, bar: &ProgressBar) -> Result<(), CodexError> {
+    debug!("ollama pull {}", model);
+    let mut child = Command::new("ollama")
+        .args(["pull", model])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error(e, "Ollama", ollama_install_hint()))?;
+
+    // Progress is redrawn in place, so split on carriage returns as well
+    // as newlines and drop the cursor-movement escapes
+    let stdout = child.stdout.take();
+    let stdout_bar = bar.clone();
+    let echo = thread::spawn(move || {
+        if let Some(out) = stdout {
+            for line in BufReader::new(out).lines().map_while(Result::ok) {
+                stdout_bar.println(line);
+            }
+        }
+    });
+    let mut last_status = String::new();
+    if let Some(stderr) = child.stderr.take() {
+        for chunk in BufReader::new(stderr).split(b'\n').map_while(Result::ok) {
+            for line in String::from_utf8_lossy(&chunk).split('\r') {
+                let line = console::strip_ansi_codes(line);
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                match parse_pull_line(line) {
+                    Some((percent, status)) => {
+                        bar.set_position(percent);
+                        bar.set_message(status);
+                    }
+                    None if line != last_status => {
+                        bar.println(line);
+                        last_status = line.to_string();
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+    let _ = echo.join();
+
+    let status = wait_with_timeout(&mut child, None)?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("Failed to pull model '{}'", model).into())
+    }
+}
+
+/// Pull through the API, which streams `{"status", "total", "completed"}`
+/// objects and ends with `{"status": "success"}`
+fn pull_model_http(model: &str, bar: &ProgressBar) -> Result<(), CodexError> {
+    let url = format!("{}/api/pull", ollama_url());
+    debug!("POST {} ({})", url, model);
+    let cancel = Cancellable::request();
+    let reply = http_client()
+        .post(&url)
+        .json(&serde_json::json!({ "model": model, "stream": true }))
+        .send()
+        .map_err(http_error)?;
+    if !reply.status().is_success() {
+        let status = reply.status();
+        let text = reply.text().unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&text)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(String::from))
+            .unwrap_or(text);
+        return Err(format!(
+            "Failed to pull model '{}': Ollama returned {}: {}",
+            model,
+            status,
+            message.trim()
+        )
+        .into());
+    }
+
+    for chunk in BufReader::new(reply).lines() {
+        if cancel.cancelled() {
+            return Err(CodexError::Cancelled);
+        }
+        let chunk: serde_json::Value = match serde_json::from_str(&chunk?) {
+            Ok(chunk) => chunk,
+            Err(_) => continue,
+        };
+        if let Some(error) = chunk["error"].as_str() {
+            return Err(format!("Failed to pull model '{}': {}", model, error).into());
+        }
+        let status = chunk["status"].as_str().unwrap_or_default();
+        if status == "success" {
+            return Ok(());
+        }
+        let total = chunk["total"].as_u64().filter(|total| *total > 0);
+        if let (Some(done), Some(total)) = (chunk["completed"].as_u64(), total) {
+            bar.set_position((done * 100 / total).min(100));
+        }
+        bar.set_message(status.to_string());
+    }
+    Err(format!("Failed to pull model '{}': the server stopped before it finished", model).into())
+}
+
+/// Print the installed models as a table, marking `current`
+fn print_models(current: &str, backend: Backend) -> Result<(), CodexError> {
+    let models = installed_models(backend)?;
+    if models.is_empty() {
+        show_warning("No models installed. Pull one with `ollama pull <model>`");
+        return Ok(());
+    }
+
+    let name_width = models.iter().map(|m| m.name.len()).max().unwrap_or(0).max(4);
+    let size_width = models.iter().map(|m| m.size.len()).max().unwrap_or(0).max(4);
+    println!(
+        "  {}",
+        style(format!("{:<name_width$}  {:<size_width$}  MODIFIED", "NAME", "SIZE")).bold()
+    );
+    for m in &models {
+        let row = format!("{:<name_width$}  {:<size_width$}  {}", m.name, m.size, m.modified);
+        if model_is_installed(current, std::slice::from_ref(&m.name)) {
+            println!("{} {}", style("*").bold().green(), style(row).bold().green());
+        } else {
+            println!("  {}", row);
+        }
+    }
+    Ok(())
+}
+
+fn model_is_installed(model: &str, installed: &[String]) -> bool {
+    // `ollama run llama3.2` resolves to the `latest` tag
+    let wanted = if model.contains(':') {
+        model.to_string()
+    } else {
+        format!("{}:latest", model)
+    };
+    installed.iter().any(|name| name == model || *name == wanted)
+}
+
+/// Prior turns of the chat, replayed to the model with every prompt
+struct Conversation {
+    turns: Vec<(String, String)>,
+    budget: usize,
+    enabled: bool,
+    /// Instructions sent ahead of every prompt; kept across `!reset`
+    system: Option<String>,
+    /// Files (path, contents) sent ahead of every prompt
+    files: Vec<(String, String)>,
+}
+
+impl Conversation {
+    fn new(enabled: bool, budget: usize, system: Option<String>) -> Self {
+        Conversation { turns: Vec::new(), budget, enabled, system, files: Vec::new() }
+    }
+
+    fn push(&mut self, role: &str, content: &str) {
+        if self.enabled {
+            self.turns.push((role.to_string(), content.to_string()));
+        }
+    }
+
+    fn reset(&mut self) {
+        self.turns.clear();
+    }
+
+    /// Drop the oldest turns until history plus the new prompt fits the budget
+    fn trim(&mut self, incoming: usize) {
+        let mut total: usize = self.turns.iter().map(|(_, c)| c.len()).sum::<usize>() + incoming;
+        while total > self.budget && !self.turns.is_empty() {
+            let (_, content) = self.turns.remove(0);
+            total -= content.len();
+        }
+    }
+
+    /// Build the stdin payload for `ollama run`. `ollama run` has no separate
+    /// system channel, so the system prompt leads the text.
+    fn payload(&mut self, prompt: &str) -> String {
+        let mut payload = String::new();
+        if let Some(system) = &self.system {
+            payload.push_str(&format!("System instructions: {}\n\n", system.trim()));
+        }
+        if !self.files.is_empty() {
+            payload.push_str("Project files for reference:\n\n");
+            for (name, contents) in &self.files {
+                payload.push_str(&fenced_file(name, contents));
+                payload.push_str("\n\n");
+            }
+        }
+        payload.push_str(&self.with_history(prompt));
+        payload
+    }
+
+    fn with_history(&mut self, prompt: &str) -> String {
+        if !self.enabled {
+            return prompt.to_string();
+        }
+        self.trim(prompt.len());
+        if self.turns.is_empty() {
+            return prompt.to_string();
+        }
+
+        let mut payload = String::from("Continue this conversation. Earlier turns:\n\n");
+        for (role, content) in &self.turns {
+            payload.push_str(&format!("{}: {}\n\n", role, content.trim_end()));
+        }
+        payload.push_str(&format!("user: {}\nassistant:", prompt));
+        payload
+    }
+}
+
+/// State that lives for the length of an interactive session
+struct Session {
+    model: String,
+    workspace: Workspace,
+    conversation: Conversation,
+    /// The last prompt sent to the model, for `!rerun`
+    last_prompt: Option<String>,
+    /// Every prompt sent to the model this session, for `!history`
+    history: Vec<(DateTime<Local>, String)>,
+    /// The last `!` command and its output (or error), for `!explain`
+    last_command: Option<(String, String)>,
+    last_response: Option<String>,
+    log: bool,
+    /// Leave generated files behind for inspection (`--keep-temp`)
+    keep_temp: bool,
+    /// Answers the prompts
+    runner: Box<dyn ModelRunner>,
+    /// Servers started this session, stopped when it's dropped
+    servers: Vec<ServerProcess>,
+    /// Pages written for HTML blocks; they stay while a browser may be
+    /// showing them and are removed when the session ends
+    html_files: Vec<PathBuf>,
+    /// Database for sql blocks, opened by the first one
+    db: Option<Connection>,
+}
+
+impl Session {
+    fn new(config: &ResolvedConfig, workspace: Workspace) -> Self {
+        Session {
+            model: config.model.clone(),
+            workspace,
+            conversation: Conversation::new(config.context, config.context_budget, config.system_prompt.clone()),
+            last_prompt: None,
+            history: Vec::new(),
+            last_command: None,
+            last_response: None,
+            log: config.log,
+            keep_temp: config.keep_temp,
+            runner: Box::new(OllamaRunner::new(config)),
+            servers: Vec::new(),
+            html_files: Vec::new(),
+            db: None,
+        }
+    }
+
+    /// Remember a completed exchange for context, `!save` and the transcript
+    fn record(&mut self, prompt: &str, response: &str) {
+        self.conversation.push("user", prompt);
+        self.conversation.push("assistant", response);
+        self.last_response = Some(response.to_string());
+        if self.log {
+            if let Err(e) = append_transcript(&self.model, prompt, response) {
+                show_warning(&format!("Could not write transcript: {}", e));
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.keep_temp {
+            return;
+        }
+        for path in &self.html_files {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Print `text`, through `$PAGER` (or `less -R`) if it's taller than the
+/// terminal. A pager that can't be started falls back to printing.
+fn show_paged(text: &str) {
+    let (rows, _) = Term::stdout().size();
+    if text.lines().count() < usize::from(rows).saturating_sub(2) {
+        println!("{}", text);
+        return;
+    }
+    let pager = env::var("PAGER")
+        .ok()
+        .and_then(|pager| shell_words::split(&pager).ok())
+        .filter(|argv| !argv.is_empty())
+        .unwrap_or_else(|| vec!["less".to_string(), "-R".to_string()]);
+    debug!("paging through {:?}", pager);
+    let mut command = Command::new(&pager[0]);
+    command.args(&pager[1..]).stdin(Stdio::piped());
+    // A bare `less` would show the colors as escape codes
+    if env::var_os("LESS").is_none() {
+        command.env("LESS", "R");
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            debug!("could not start {}: {}", pager[0], e);
+            println!("{}", text);
+            return;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // Quitting the pager early closes the pipe; that's not an error
+        let _ = writeln!(stdin, "{}", text);
+    }
+    let _ = child.wait();
+}
+
+/// Write a one-shot response for `--output-file` in the chosen format. ANSI
+/// codes only make sense on a terminal, so ansi writes markdown too.
+fn write_output_file(path: &str, prompt: &str, model: &str, response: &str, format: OutputFormat) -> Result<(), CodexError> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = match format {
+        OutputFormat::Plain => response.to_string(),
+        OutputFormat::Ansi | OutputFormat::Markdown => render(response, OutputFormat::Markdown),
+        OutputFormat::Json => json_report(prompt, model, &Ok(response.to_string())).map_err(|e| e.to_string())? + "\n",
+    };
+    fs::write(path, contents).map_err(CodexError::from)
+}
+
+/// How many of the latest prompts `!history` lists
+const HISTORY_SHOWN: usize = 20;
+
+/// The latest prompts, numbered for `!history <n>`, each cut to its first
+/// line
+fn print_history(history: &[(DateTime<Local>, String)]) {
+    println!("\n{}", style("Prompt history:").bold().cyan());
+    let skip = history.len().saturating_sub(HISTORY_SHOWN);
+    for (index, (time, prompt)) in history.iter().enumerate().skip(skip) {
+        let mut lines = prompt.trim().lines();
+        let first = lines.next().unwrap_or_default();
+        let (first, cut) = match first.char_indices().nth(72) {
+            Some((end, _)) => (&first[..end], true),
+            None => (first, lines.next().is_some()),
+        };
+        println!(
+            "  {:>3}  {}  {}{}",
+            index + 1,
+            style(time.format("%H:%M:%S")).dim(),
+            first,
+            if cut { "…" } else { "" }
+        );
+    }
+    println!("{}", style("Run one again with !history <n>").dim());
+}
+
+/// Strip the ``` fence lines, keeping only the text between them
+fn strip_fences(response: &str) -> String {
+    response
+        .lines()
+        .filter(|line| !line.trim().starts_with("```"))
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+fn save_response(path: &str, response: &str) -> Result<(), CodexError> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Markdown files keep the fences so code blocks still render
+    let is_markdown = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"));
+    let contents = if is_markdown {
+        response.to_string()
+    } else {
+        strip_fences(response)
+    };
+
+    fs::write(path, contents).map_err(CodexError::from)
+}
+
+/// A file labeled with its path, in a code block tagged by its extension
+fn fenced_file(path: &str, contents: &str) -> String {
+    let lang = Path::new(path).extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    format!("{}:\n```{}\n{}\n```", path, lang, contents.trim_end())
+}
+
+/// Read the files matching `pattern` into `files`, skipping ones already
+/// there and stopping at `MAX_ATTACHMENT_BYTES` in total. Returns the paths
+/// added.
+fn add_context_files(pattern: &str, files: &mut Vec<(String, String)>) -> Result<Vec<String>, CodexError> {
+    let paths = glob::glob(pattern).map_err(|e| format!("Bad pattern {}: {}", pattern, e))?;
+    let mut total: usize = files.iter().map(|(_, contents)| contents.len()).sum();
+    let mut added = Vec::new();
+    for path in paths.filter_map(Result::ok).filter(|p| p.is_file()) {
+        let name = path.display().to_string();
+        if files.iter().any(|(existing, _)| *existing == name) {
+            continue;
+        }
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                show_warning(&format!("Skipping {}: {}", name, e));
+                continue;
+            }
+        };
+        if total + contents.len() > MAX_ATTACHMENT_BYTES as usize {
+            show_warning(&format!(
+                "Skipping {} and later matches: context files are limited to {} KB in total",
+                name,
+                MAX_ATTACHMENT_BYTES / 1024
+            ));
+            break;
+        }
+        total += contents.len();
+        files.push((name.clone(), contents));
+        added.push(name);
+    }
+    if added.is_empty() && !files.iter().any(|(name, _)| glob::Pattern::new(pattern).is_ok_and(|p| p.matches(name))) {
+        return Err(format!("No files match {}", pattern).into());
+    }
+    Ok(added)
+}
+
+/// Print the context files and their sizes
+fn print_context_files(files: &[(String, String)]) {
+    if files.is_empty() {
+        show_warning("No context files. Add some with !context add <glob>");
+        return;
+    }
+    let total: usize = files.iter().map(|(_, contents)| contents.len()).sum();
+    println!("\n{}", style(format!("Context files ({} KB):", total.div_ceil(1024))).bold());
+    for (name, contents) in files {
+        println!("  {} {}", name, style(format!("({} KB)", contents.len().div_ceil(1024))).dim());
+    }
+}
+
+/// Largest file an `@path` reference will inline
+const MAX_ATTACHMENT_BYTES: u64 = 100 * 1024;
+
+/// Inline the files named by `@path` words. Each reference becomes the bare
+/// path and the file follows the prompt in a fenced block, tagged by its
+/// extension. Words that aren't readable files are left alone, so
+/// `@someone` still reads as usual. Returns the prompt and the attached paths.
+fn expand_file_references(prompt: &str) -> (String, Vec<String>) {
+    let mut text = String::new();
+    let mut attached: Vec<String> = Vec::new();
+    let mut blocks = String::new();
+
+    for piece in prompt.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end();
+        // Allow `@file.rs,` and `(@file.rs)` in a sentence
+        let open = word.len() - word.trim_start_matches('(').len();
+        let Some(reference) = word[open..].strip_prefix('@') else {
+            text.push_str(piece);
+            continue;
+        };
+        let path = reference.trim_end_matches([',', '.', '?', '!', ')', ';', ':']);
+        if !Path::new(path).is_file() {
+            text.push_str(piece);
+            continue;
+        }
+
+        let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        let contents = if size > MAX_ATTACHMENT_BYTES {
+            show_warning(&format!(
+                "Not attaching {}: {} KB is over the {} KB limit",
+                path,
+                size / 1024,
+                MAX_ATTACHMENT_BYTES / 1024
+            ));
+            None
+        } else {
+            fs::read_to_string(path)
+                .inspect_err(|e| show_warning(&format!("Not attaching {}: {}", path, e)))
+                .ok()
+        };
+        text.push_str(&piece[..open]);
+        text.push_str(&piece[open + 1..]);
+
+        if let Some(contents) = contents.filter(|_| !attached.iter().any(|a| a == path)) {
+            blocks.push_str(&format!("\n\n{}", fenced_file(path, &contents)));
+            attached.push(path.to_string());
+        }
+    }
+
+    text.push_str(&blocks);
+    (text, attached)
+}
+
+/// A file name given in a comment on the first line of a block, e.g.
+/// `# file: app.py`, `// filename: src/main.rs` or `<!-- file: index.html -->`
+fn filename_hint(code: &str) -> Option<String> {
+    let first = code.lines().next()?.trim();
+    let comment = ["#", "//", "--", "<!--", "/*", ";"]
+        .iter()
+        .find_map(|marker| first.strip_prefix(marker))?
+        .trim()
+        .trim_end_matches("-->")
+        .trim_end_matches("*/")
+        .trim();
+    let name = comment
+        .strip_prefix("filename:")
+        .or_else(|| comment.strip_prefix("file:"))?
+        .trim();
+    (!name.is_empty() && !name.contains(char::is_whitespace)).then(|| name.to_string())
+}
+
+/// Whether `path` is relative and never climbs out of the directory it's
+/// joined to
+fn stays_inside(path: &str) -> bool {
+    Path::new(path).components().all(|c| matches!(c, std::path::Component::Normal(_)))
+}
+
+/// Write each code block of `response` into `dir`, named by its fence's
+/// `lang:path`, its filename hint, or as `block<N>.<ext>`. Returns the paths written.
+fn extract_blocks_to(response: &str, dir: &Path) -> Result<Vec<PathBuf>, CodexError> {
+    fs::create_dir_all(dir)?;
+    let mut written = Vec::new();
+    for (i, block) in extract_code_blocks(response).iter().enumerate() {
+        let fallback = format!("block{}.{}", i + 1, normalize_language(&block.lang).map_or("txt", Lang::extension));
+        let name = match block.filename.clone().or_else(|| filename_hint(&block.code)) {
+            // Hints may name subdirectories, but must stay inside `dir`
+            Some(hint) if stays_inside(&hint) => hint,
+            Some(hint) => {
+                show_warning(&format!("Ignoring file name {} outside {}", hint, dir.display()));
+                fallback
+            }
+            None => fallback,
+        };
+        let path = dir.join(&name);
+        if path.exists() && !confirm(&format!("{} exists. Overwrite it?", path.display())) {
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, &block.code).map_err(|e| format!("{}: {}", path.display(), e))?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Put `text` on the system clipboard. Fails rather than panics where
+/// there is no clipboard (headless or SSH sessions).
+fn copy_to_clipboard(text: &str) -> Result<(), CodexError> {
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .map_err(|e| format!("Could not copy to clipboard: {}", e).into())
+}
+
+/// The code blocks of a response, without fences, one after another
+fn code_only(response: &str) -> String {
+    extract_code_blocks(response)
+        .into_iter()
+        .map(|block| block.code.trim_end().to_string())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Open `$EDITOR` on a scratch file and return what the user saved
+fn compose_in_editor() -> Result<String, CodexError> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().ok_or_else(|| CodexError::from("EDITOR is empty"))?;
+
+    let file = tempfile::Builder::new()
+        .prefix("codexcli-prompt-")
+        .suffix(".md")
+        .tempfile()
+        ?;
+
+    let status = Command::new(program)
+        .args(parts)
+        .arg(file.path())
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .map_err(|e| format!("Failed to launch {}: {}", program, e))?;
+
+    if !status.success() {
+        return Err(format!("{} exited with status: {}", program, status).into());
+    }
+
+    fs::read_to_string(file.path()).map_err(CodexError::from)
+}
+
+/// Generation settings for Ollama; None keeps the model's own default
+#[derive(Debug, Clone, Default)]
+struct ModelOptions {
+    /// `num_predict`: the most tokens to generate
+    max_tokens: Option<u32>,
+    temperature: Option<f64>,
+    /// Anything else from `--model-option`, e.g. `seed` or `top_p`
+    extra: Vec<(String, serde_json::Value)>,
+}
+
+impl ModelOptions {
+    fn from_config(config: &ResolvedConfig) -> Self {
+        ModelOptions {
+            max_tokens: config.max_tokens,
+            temperature: config.temperature,
+            extra: config.model_options.clone(),
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        self.max_tokens.is_none() && self.temperature.is_none() && self.extra.is_empty()
+    }
+
+    /// The `options` object of an Ollama API request
+    fn to_json(&self) -> serde_json::Value {
+        let mut options = serde_json::Map::new();
+        if let Some(n) = self.max_tokens {
+            options.insert("num_predict".to_string(), n.into());
+        }
+        if let Some(t) = self.temperature {
+            options.insert("temperature".to_string(), t.into());
+        }
+        for (key, value) in &self.extra {
+            options.insert(key.clone(), value.clone());
+        }
+        options.into()
+    }
+}
+
+const CLI_OPTIONS_ERROR: &str =
+    "--temperature, --max-tokens and --model-option need --backend http: `ollama run` can't take them";
+
+/// Ollama options that take whole numbers
+const INTEGER_OPTIONS: &[&str] = &[
+    "mirostat", "num_batch", "num_ctx", "num_gpu", "num_keep", "num_predict", "num_thread", "repeat_last_n", "seed",
+    "top_k",
+];
+
+/// Ollama options that take any number
+const NUMBER_OPTIONS: &[&str] = &[
+    "frequency_penalty", "min_p", "mirostat_eta", "mirostat_tau", "presence_penalty", "repeat_penalty", "temperature",
+    "tfs_z", "top_p", "typical_p",
+];
+
+/// Clap parser for `--model-option key=value`. Known numeric options must
+/// be numbers, `stop` becomes a one-element list, and other keys take a
+/// number, true/false or else the text as given.
+fn parse_model_option(value: &str) -> Result<(String, serde_json::Value), String> {
+    let (key, raw) = value
+        .split_once('=')
+        .map(|(key, raw)| (key.trim(), raw.trim()))
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or_else(|| format!("expected key=value, got '{}'", value))?;
+    let parsed = if INTEGER_OPTIONS.contains(&key) {
+        raw.parse::<i64>()
+            .map(serde_json::Value::from)
+            .map_err(|_| format!("{} must be a whole number, got '{}'", key, raw))?
+    } else if NUMBER_OPTIONS.contains(&key) {
+        raw.parse::<f64>()
+            .map(serde_json::Value::from)
+            .map_err(|_| format!("{} must be a number, got '{}'", key, raw))?
+    } else if key == "stop" {
+        serde_json::json!([raw])
+    } else if let Ok(n) = raw.parse::<i64>() {
+        n.into()
+    } else if let Ok(n) = raw.parse::<f64>() {
+        n.into()
+    } else if let Ok(b) = raw.parse::<bool>() {
+        b.into()
+    } else {
+        raw.into()
+    };
+    Ok((key.to_string(), parsed))
+}
+
+/// Clap parser for `--temperature`
+fn parse_temperature(value: &str) -> Result<f64, String> {
+    let t: f64 = value.parse().map_err(|_| format!("'{}' is not a number", value))?;
+    if (0.0..=2.0).contains(&t) {
+        Ok(t)
+    } else {
+        Err("must be between 0.0 and 2.0".to_string())
+    }
+}
+
+/// Base URL of the Ollama server, from `OLLAMA_HOST` like the CLI uses
+fn ollama_url() -> String {
+    let host = env::var("OLLAMA_HOST").ok().filter(|h| !h.is_empty()).unwrap_or_else(|| "127.0.0.1:11434".to_string());
+    if host.contains("://") {
+        host.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", host.trim_end_matches('/'))
+    }
+}
+
+/// Tokens the server says it generated, and how long generating them took
+#[derive(Debug, Clone, Copy)]
+struct EvalCount {
+    tokens: u64,
+    duration: Duration,
+}
+
+/// A finished response, with the server's token count when it reports one
+/// (the HTTP API does, `ollama run` doesn't)
+struct Reply {
+    text: String,
+    eval: Option<EvalCount>,
+}
+
+/// Run the model with `ollama run` (the CLI backend) and hand each line of
+/// output to `on_line` as soon as it arrives. Returns the complete response
+/// once the model finishes. `ollama run` can't take generation options, so
+/// with any set this fails rather than quietly using the HTTP API.
+fn stream_model<F: FnMut(&str)>(prompt: &str, model: &str, options: &ModelOptions, mut on_line: F) -> Result<Reply, CodexError> {
+    if !options.is_default() {
+        return Err(CodexError::Config(CLI_OPTIONS_ERROR.to_string()));
+    }
+    debug!("ollama run {} ({} bytes of prompt)", model, prompt.len());
+    trace!("prompt:\n{}", prompt);
+    // Spawning is the re-check for an ollama removed since startup
+    let mut child = Command::new("ollama")
+        .args(["run", model])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| spawn_error(e, "Ollama", ollama_install_hint()))?;
+
+    // Dropping stdin after writing signals end of prompt to ollama
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(prompt.as_bytes())?;
+    }
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| CodexError::from("Failed to capture model output"))?;
+    let cancel = Cancellable::track(&child);
+
+    let mut response = String::new();
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        on_line(&line);
+        response.push_str(&line);
+        response.push('\n');
+    }
+
+    let out = child.wait_with_output()?;
+    if cancel.cancelled() {
+        Err(CodexError::Cancelled)
+    } else if out.status.success() {
+        Ok(Reply { text: response, eval: None })
+    } else {
+        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_string();
+        if is_connection_error(&stderr) {
+            Err(CodexError::ModelUnavailable(stderr))
+        } else if is_model_not_found(&stderr) {
+            Err(CodexError::ModelNotFound(model.to_string()))
+        } else {
+            Err(CodexError::Other(stderr))
+        }
+    }
+}
+
+/// Shared client for the Ollama HTTP API. No overall timeout, since a
+/// generation can take minutes.
+fn http_client() -> &'static reqwest::blocking::Client {
+    static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::blocking::Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(None)
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// The `--backend` given, or without one the HTTP API if the server
+/// answers and the `ollama` CLI otherwise
+fn pick_backend(choice: Option<Backend>) -> Backend {
+    choice.unwrap_or_else(|| if server_reachable() { Backend::Http } else { Backend::Cli })
+}
+
+/// Whether an Ollama server answers at `ollama_url`, to pick the backend
+fn server_reachable() -> bool {
+    http_client()
+        .get(format!("{}/api/version", ollama_url()))
+        .timeout(Duration::from_millis(500))
+        .send()
+        .is_ok_and(|response| response.status().is_success())
+}
+
+/// A failed request as a `CodexError`: an unreachable server is
+/// `ModelUnavailable` so `run_model` retries it
+fn http_error(e: reqwest::Error) -> CodexError {
+    if e.is_connect() || e.is_timeout() {
+        CodexError::ModelUnavailable(format!("Could not connect to Ollama at {}: {}", ollama_url(), e))
+    } else {
+        CodexError::Other(format!("Ollama request failed: {}", e))
+    }
+}
+
+/// Stream a response from the server's `/api/generate`. Tokens arrive as
+/// NDJSON chunks and are handed to `on_line` a line at a time.
+fn stream_model_http<F: FnMut(&str)>(prompt: &str, model: &str, options: &ModelOptions, mut on_line: F) -> Result<Reply, CodexError> {
+    let url = format!("{}/api/generate", ollama_url());
+    debug!("POST {} ({} bytes of prompt, options {})", url, prompt.len(), options.to_json());
+    trace!("prompt:\n{}", prompt);
+    let body = serde_json::json!({
+        "model": model,
+        "prompt": prompt,
+        "stream": true,
+        "options": options.to_json(),
+    });
+    let cancel = Cancellable::request();
+    let reply = http_client().post(&url).json(&body).send().map_err(http_error)?;
+
+    // Errors come back as {"error": "..."} with a failing status
+    let status = reply.status();
+    if !status.is_success() {
+        let text = reply.text().unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&text)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(String::from))
+            .unwrap_or(text);
+        if is_model_not_found(&message) {
+            return Err(CodexError::ModelNotFound(model.to_string()));
+        }
+        return Err(CodexError::Other(format!("Ollama returned {}: {}", status, message.trim())));
+    }
+
+    let mut response = String::new();
+    let mut pending = String::new();
+    let mut eval = None;
+    for chunk in BufReader::new(reply).lines() {
+        if cancel.cancelled() {
+            // Dropping the response closes the connection, which stops the
+            // generation on the server
+            return Err(CodexError::Cancelled);
+        }
+        let chunk: serde_json::Value = match serde_json::from_str(&chunk?) {
+            Ok(chunk) => chunk,
+            Err(_) => continue,
+        };
+        if let Some(error) = chunk["error"].as_str() {
+            return Err(CodexError::Other(format!("Ollama error: {}", error)));
+        }
+        pending.push_str(chunk["response"].as_str().unwrap_or_default());
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            on_line(line.trim_end_matches('\n'));
+            response.push_str(&line);
+        }
+        if chunk["done"].as_bool() == Some(true) {
+            // The last chunk counts the generated tokens; the duration is in
+            // nanoseconds
+            if let (Some(tokens), Some(nanos)) = (chunk["eval_count"].as_u64(), chunk["eval_duration"].as_u64()) {
+                eval = Some(EvalCount { tokens, duration: Duration::from_nanos(nanos) });
+            }
+            break;
+        }
+    }
+    if cancel.cancelled() {
+        return Err(CodexError::Cancelled);
+    }
+    if !pending.is_empty() {
+        on_line(&pending);
+        response.push_str(&pending);
+        response.push('\n');
+    }
+    Ok(Reply { text: response, eval })
+}
+
+/// Machine-readable result of one prompt for `--json`
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    prompt: &'a str,
+    model: &'a str,
+    response: Option<&'a str>,
+    code_blocks: Vec<CodeBlock>,
+    error: Option<String>,
+}
+
+fn json_report(prompt: &str, model: &str, result: &Result<String, CodexError>) -> serde_json::Result<String> {
+    let report = JsonReport {
+        prompt,
+        model,
+        response: result.as_deref().ok(),
+        code_blocks: result.as_deref().map(extract_code_blocks).unwrap_or_default(),
+        error: result.as_ref().err().map(ToString::to_string),
+    };
+    serde_json::to_string(&report)
+}
+
+fn print_json_report(prompt: &str, model: &str, result: &Result<String, CodexError>) {
+    match json_report(prompt, model, result) {
+        Ok(json) => println!("{}", json),
+        Err(e) => eprintln!("Failed to encode JSON: {}", e),
+    }
+}
+
+/// How many times to retry when the Ollama daemon can't be reached
+const MODEL_RETRIES: u32 = 3;
+
+/// Wait before the first retry; each later one waits twice as long
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+fn is_connection_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    error.contains("connection refused")
+        || error.contains("could not connect")
+        || error.contains("couldn't connect")
+        || error.contains("is it running")
+}
+
+/// Whether Ollama's error says the model hasn't been pulled, e.g. "model
+/// 'x' not found, try pulling it first"
+fn is_model_not_found(error: &str) -> bool {
+    let error = error.to_lowercase();
+    (error.contains("model") && error.contains("not found")) || error.contains("try pulling it")
+}
+
+/// Make one request to the model with `attempt`, retrying up to `retries`
+/// times with exponential backoff from `backoff` while the daemon is
+/// unreachable (e.g. `ollama serve` still starting up)
+fn run_model<T>(
+    retries: u32,
+    backoff: Duration,
+    mut attempt: impl FnMut() -> Result<T, CodexError>,
+) -> Result<T, CodexError> {
+    let mut delay = backoff;
+    let mut tries = 0;
+    loop {
+        match attempt() {
+            Err(CodexError::ModelUnavailable(e)) if tries < retries => {
+                tries += 1;
+                debug!("ollama unreachable ({}), retrying in {:?}", e, delay);
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(CodexError::ModelUnavailable(e)) => {
+                return Err(CodexError::ModelUnavailable(format!(
+                    "{}\nCould not reach Ollama after {} attempts. Is `ollama serve` running?",
+                    e,
+                    tries + 1
+                )));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn process_prompt(prompt: &str, config: &ResolvedConfig, session: &mut Session) {
+    let raw = config.raw;
+    let model = session.model.clone();
+    let model = model.as_str();
+
+    if prompt.trim() == "!reset" {
+        session.conversation.reset();
+        if !raw {
+            show_success("Conversation history cleared");
+        }
+        return;
+    }
+
+    // Only the screen is cleared; the conversation carries on
+    if prompt.trim() == "!clear" {
+        let _ = Term::stdout().clear_screen();
+        if !raw && !config.no_banner {
+            print_banner(config.banner_font.as_deref());
+        }
+        return;
+    }
+
+    if prompt.trim() == "!explain" {
+        let Some((command, output)) = session.last_command.clone() else {
+            show_warning("No command output to explain. Run a !command first.");
+            return;
+        };
+        // Keep the end of long output, where errors and summaries usually are
+        let output = match output.char_indices().nth_back(config.context_budget) {
+            Some((cut, _)) => format!("[...]\n{}", &output[cut..]),
+            None => output,
+        };
+        let request = format!(
+            "I ran `{}` and got this output. Please explain what it means, and if something went wrong, why and how to fix it.\n\n```\n{}\n```",
+            command,
+            output.trim_end()
+        );
+        process_prompt(&request, config, session);
+        return;
+    }
+
+    if let Some(args) = prompt
+        .trim()
+        .strip_prefix("!context")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        let files = &mut session.conversation.files;
+        match args.trim().split_once(char::is_whitespace).unwrap_or((args.trim(), "")) {
+            ("" | "list", _) => print_context_files(files),
+            ("add", pattern) if !pattern.trim().is_empty() => match add_context_files(pattern.trim(), files) {
+                Ok(added) => show_success(&format!("Added {} file(s) to the context", added.len())),
+                Err(e) => show_error(&e.to_string()),
+            },
+            ("remove", pattern) if !pattern.trim().is_empty() => {
+                let before = files.len();
+                let matcher = glob::Pattern::new(pattern.trim()).ok();
+                files.retain(|(name, _)| name != pattern.trim() && !matcher.as_ref().is_some_and(|m| m.matches(name)));
+                show_success(&format!("Removed {} file(s) from the context", before - files.len()));
+            }
+            _ => show_warning("Usage: !context [list | add <glob> | remove <path or glob>]"),
+        }
+        return;
+    }
+
+    if prompt.trim() == "!rerun" {
+        match session.last_prompt.clone() {
+            Some(last) => process_prompt(&last, config, session),
+            None => show_warning("No previous prompt to rerun"),
+        }
+        return;
+    }
+
+    if let Some(arg) = prompt
+        .trim()
+        .strip_prefix("!history")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        match arg.trim() {
+            "" if session.history.is_empty() => show_warning("No prompts yet"),
+            "" => print_history(&session.history),
+            n => match n.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|i| session.history.get(i)) {
+                Some((_, earlier)) => process_prompt(&earlier.clone(), config, session),
+                None => show_warning(&format!(
+                    "No prompt {} in the history. Usage: !history [n] (1-{})",
+                    n,
+                    session.history.len()
+                )),
+            },
+        }
+        return;
+    }
+
+    if prompt.trim() == "!models" {
+        if let Err(e) = print_models(model, pick_backend(config.backend)) {
+            show_error(&format!("Could not list models: {}", e));
+        }
+        return;
+    }
+
+    if let Some(name) = prompt
+        .trim()
+        .strip_prefix("!switch")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        let name = name.trim();
+        if name.is_empty() {
+            show_warning("Usage: !switch <model>");
+            return;
+        }
+        match ensure_model_installed(name, pick_backend(config.backend)) {
+            Ok(()) => {
+                session.model = name.to_string();
+                show_success(&format!("Switched to model '{}'", name));
+            }
+            Err(e) => show_error(&e.to_string()),
+        }
+        return;
+    }
+
+    if let Some(text) = prompt
+        .trim()
+        .strip_prefix("!system")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        let text = text.trim();
+        if text.is_empty() {
+            match &session.conversation.system {
+                Some(system) => println!("\n{} {}", style("System prompt:").bold().cyan(), system),
+                None => show_warning("No system prompt set. Usage: !system <text>"),
+            }
+            return;
+        }
+        session.conversation.system = Some(text.to_string());
+        if !raw {
+            show_success("System prompt updated");
+        }
+        return;
+    }
+
+    if let Some(rest) = prompt
+        .trim()
+        .strip_prefix("!tpl")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        let (name, input) = rest.trim().split_once(char::is_whitespace).unwrap_or((rest.trim(), ""));
+        if name.is_empty() || name == "list" {
+            match list_templates() {
+                Ok(names) if names.is_empty() => {
+                    show_warning("No templates yet. Save them as files in ~/.config/codexcli/templates/")
+                }
+                Ok(names) => {
+                    println!("\n{}", style("Templates:").bold().cyan());
+                    for name in names {
+                        println!("  {}", name);
+                    }
+                    println!("{}", style("Usage: !tpl <name> <args>").dim());
+                }
+                Err(e) => show_error(&e.to_string()),
+            }
+            return;
+        }
+        match expand_template(name, input) {
+            Ok(text) => process_prompt(&text, config, session),
+            Err(e) => show_error(&e.to_string()),
+        }
+        return;
+    }
+
+    if prompt.trim() == "!stop" {
+        if session.servers.is_empty() {
+            show_warning("No servers running");
+        }
+        for mut server in session.servers.drain(..) {
+            server.stop();
+            show_success(&format!("Stopped {} (pid {})", server.name, server.pid()));
+        }
+        return;
+    }
+
+    if prompt.trim() == "!edit" {
+        match compose_in_editor() {
+            Ok(text) if !text.trim().is_empty() => process_prompt(text.trim(), config, session),
+            Ok(_) => show_warning("Empty prompt, nothing sent"),
+            Err(e) => show_warning(&format!("Edit cancelled: {}", e)),
+        }
+        return;
+    }
+
+    if matches!(prompt.trim(), "!copy" | "!copy-code") {
+        let Some(response) = &session.last_response else {
+            show_warning("No response to copy");
+            return;
+        };
+        let text = if prompt.trim() == "!copy" { response.clone() } else { code_only(response) };
+        if text.is_empty() {
+            show_warning("The last response has no code blocks");
+            return;
+        }
+        match copy_to_clipboard(&text) {
+            Ok(()) => show_success("Copied to clipboard"),
+            Err(e) => show_warning(&e.to_string()),
+        }
+        return;
+    }
+
+    if let Some(dir) = prompt
+        .trim()
+        .strip_prefix("!extract")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        let Some(response) = &session.last_response else {
+            show_warning("No response to extract code from");
+            return;
+        };
+        let dir = match dir.trim() {
+            "" => session.workspace.path().to_path_buf(),
+            dir => PathBuf::from(dir),
+        };
+        match extract_blocks_to(response, &dir) {
+            Ok(written) if written.is_empty() => show_warning("No code blocks written"),
+            Ok(written) => {
+                show_success(&format!("Wrote {} file(s):", written.len()));
+                for path in written {
+                    println!("  {}", path.display());
+                }
+            }
+            Err(e) => show_error(&e.to_string()),
+        }
+        return;
+    }
+
+    if let Some(path) = prompt
+        .trim()
+        .strip_prefix("!save")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    {
+        let path = path.trim();
+        match (&session.last_response, path.is_empty()) {
+            (None, _) => show_warning("No response to save"),
+            (Some(_), true) => show_warning("Usage: !save <file>"),
+            (Some(response), false) => match save_response(path, response) {
+                Ok(()) => show_success(&format!("Response saved to {}", path)),
+                Err(e) => show_error(&e.to_string()),
+            },
+        }
+        return;
+    }
+
+    if let Some(c) = prompt.strip_prefix('!') {
+        let c = c.trim();
+        let argv = match parse_command(c) {
+            Ok(argv) => argv,
+            Err(e) => {
+                show_error(&e.to_string());
+                return;
+            }
+        };
+        let argv = command_argv(c, config.shell.as_deref()).unwrap_or(argv);
+        if config.dry_run {
+            // `!` commands run where codexcli was started, unlike code blocks
+            let cwd = env::current_dir().unwrap_or_default();
+            let workdir = session.workspace.path();
+            println!(
+                "{}\nWould run: {:?}\nCommand runs in: {}{}",
+                style("(dry run) nothing was executed").dim(),
+                argv,
+                cwd.display(),
+                if cwd == workdir {
+                    String::new()
+                } else {
+                    format!(" (the current directory; code blocks run in {})", workdir.display())
+                }
+            );
+            return;
+        }
+        if is_mutating_command(c) && !config.yes && !confirm(&format!("Run this command? {:?}", argv)) {
+            show_warning("Command skipped");
+            return;
+        }
+        if !raw {
+            println!("{} {}", style("Executing command:").bold().yellow(), style(c).white());
+        }
+        let result = execute_command(c, config.shell.as_deref());
+        session.last_command = Some((
+            c.to_string(),
+            match &result {
+                Ok(out) => out.summary(),
+                Err(e) => e.to_string(),
+            },
+        ));
+        let failed = match result {
+            Ok(out) => {
+                print_command_output(&out, raw);
+                !out.status.success()
+            }
+            Err(e) => {
+                show_error(&e.to_string());
+                true
+            }
+        };
+        // Try to fix common command issues
+        if let Some(fixed) = fix_command(c).ok().filter(|_| failed) {
+            show_error_recovery("Attempting to fix the command...");
+            show_warning(&format!("Trying fixed command: {}", fixed));
+            match execute_command(&fixed, config.shell.as_deref()) {
+                Ok(out) => {
+                    print_command_output(&out, raw);
+                    if out.status.success() {
+                        show_success("Command fixed and executed successfully");
+                    }
+                }
+                Err(e) => show_error(&e.to_string()),
+            }
+        }
+        return;
+    }
+
+    // Check for special commands in the prompt
+    if config.dry_run && special_command_plan(prompt, config).is_some() {
+        match dry_run_code_block(prompt, "", None, &session.workspace, config) {
+            Ok(plan) => println!("{}", plan),
+            Err(e) => show_error(&e.to_string()),
+        }
+        return;
+    }
+
+    if prompt.trim() == "create-react-app" {
+        match setup_react_environment(&session.workspace) {
+            Ok(_) => println!("\n{}", style("React application created successfully. Use 'npm start' to run the development server.").bold().green()),
+            Err(e) => println!("\n{} {}", style("Error:").bold().red(), style(e).red()),
+        }
+        return;
+    }
+
+    if prompt.trim() == "npm start" {
+        match start_react_server(&session.workspace) {
+            Ok((msg, server)) => {
+                session.servers.push(server);
+                println!("\n{}", style(msg).bold().green());
+            }
+            Err(e) => println!("\n{} {}", style("Error:").bold().red(), style(e).red()),
+        }
+        return;
+    }
+
+    if prompt.trim().starts_with("start-server") {
+        let port = server_port(prompt, config.port);
+        match start_local_server(port, config.bind.as_deref(), &session.workspace) {
+            Ok((msg, server)) => {
+                session.servers.push(server);
+                println!("\n{}", style(msg).bold().green());
+            }
+            Err(e) => println!("\n{} {}", style("Error:").bold().red(), style(e).red()),
+        }
+        return;
+    }
+
+    if !raw {
+        println!("{} {}", style(format!("{}Prompt:", icon("🤖"))).bold().cyan(), style(prompt).white());
+        println!();
+    }
+
+    session.last_prompt = Some(prompt.to_string());
+    session.history.push((Local::now(), prompt.to_string()));
+    let (request, attached) = expand_file_references(prompt);
+    if !raw {
+        for path in &attached {
+            println!("{} {}", style(format!("{}Attached", icon("📎"))).dim(), style(path).white());
+        }
+    }
+    let payload = session.conversation.payload(&request);
+    // A Ctrl-C from an earlier prompt doesn't carry over to this one
+    CANCEL.reset();
+
+    if config.format == OutputFormat::Json {
+        let result = session.runner.run(&payload, model, &mut |_| {});
+        // The report keeps the response verbatim, but the history gets just
+        // the answer, as in the other formats
+        if let Ok(output) = &result {
+            session.record(&request, &strip_thinking(output));
+        }
+        if !config.quiet {
+            print_json_report(prompt, model, &result);
+        }
+        if let Ok(output) = &result {
+            export_block_records(config, &block_records(prompt, output));
+        }
+        return;
+    }
+
+    let mut spinner = if raw { None } else { Some(show_timed_spinner(&format!("{} thinking…", model))) };
+    let mut started = false;
+    let mut renderer = Renderer::new(config.format);
+    // Paged responses are collected while the spinner keeps going, then
+    // shown all at once
+    let page = config.pager && !raw && io::stdout().is_terminal();
+    let mut paged = Vec::new();
+    let asked = Instant::now();
+    let ai = session.runner.run(&payload, model, &mut |line| {
+        if page {
+            paged.extend(renderer.line(line));
+            return;
+        }
+        // Stop the spinner as soon as the first token arrives
+        if let Some(sp) = spinner.take() {
+            sp.finish_and_clear();
+        }
+        if !raw && !started {
+            print_response_heading();
+            started = true;
+        }
+        if let Some(line) = renderer.line(line).filter(|_| !config.quiet) {
+            println!("{}", line);
+        }
+    });
+    let elapsed = asked.elapsed();
+    if let Some(sp) = spinner {
+        sp.finish_and_clear();
+    }
+    if let Some(rest) = renderer.finish().filter(|_| !config.quiet) {
+        if page {
+            paged.push(rest);
+        } else {
+            println!("{}", rest);
+        }
+    }
+    if !paged.is_empty() {
+        print_response_heading();
+        started = true;
+        show_paged(&paged.join("\n"));
+    }
+    // Reasoning isn't part of the answer: keep it out of history and don't
+    // run code from it
+    let ai = ai.map(|output| strip_thinking(&output));
+
+    // Filled in as blocks run, and written out once they're done
+    let mut records = match &ai {
+        Ok(output) if config.export_blocks.is_some() => block_records(prompt, output),
+        _ => Vec::new(),
+    };
+
+    if let Ok(output) = &ai {
+        session.record(&request, output);
+        if config.copy {
+            match copy_to_clipboard(output) {
+                Ok(()) if !raw => show_success("Copied to clipboard"),
+                Ok(()) => {}
+                Err(e) => show_warning(&e.to_string()),
+            }
+        }
+    }
+
+    match ai {
+        Ok(output) => {
+            if !raw {
+                if !started {
+                    print_response_heading();
+                }
+                print_divider();
+                if config.stats {
+                    println!("{}", style(response_stats(&output, elapsed, session.runner.last_eval())).dim());
+                }
+
+                let blocks = extract_code_blocks(&output);
+                let total = blocks.len();
+                // With several blocks, ask about each one as it comes up
+                let mut ask_each = config.auto_execute == AutoExecute::Ask
+                    && total > 1
+                    && config.allow_exec
+                    && !config.dry_run;
+                let execute = !blocks.is_empty()
+                    && match config.auto_execute {
+                        _ if config.dry_run => true,
+                        _ if !config.allow_exec => {
+                            if config.auto_execute == AutoExecute::Always {
+                                show_warning("auto_execute is \"always\" but code execution is disabled, so nothing runs. Add --allow-exec (or allow_exec = true) to run code blocks.");
+                            } else {
+                                println!(
+                                    "\n{}",
+                                    style("Code execution is disabled. Re-run with --allow-exec (or set allow_exec = true in the config) to run code blocks.").dim()
+                                );
+                            }
+                            false
+                        }
+                        AutoExecute::Never => false,
+                        AutoExecute::Ask => ask_each || confirm("Found a code block. Execute it?"),
+                        AutoExecute::Always => true,
+                    };
+                if execute {
+                    // Registered for the whole batch, so Ctrl-C between blocks
+                    // stops the rest instead of exiting
+                    CANCEL.reset();
+                    let batch = Cancellable::request();
+                    let mut ran = 0;
+                    let mut stopped = false;
+                    for (index, CodeBlock { lang, code, filename, unterminated }) in blocks.into_iter().enumerate() {
+                        if batch.cancelled() {
+                            stopped = true;
+                            break;
+                        }
+                        let lang = if lang.is_empty() {
+                            // A ```:app.py fence says it by the extension
+                            let by_name = filename
+                                .as_deref()
+                                .and_then(|name| Path::new(name).extension())
+                                .map(|ext| ext.to_string_lossy().into_owned())
+                                .filter(|ext| normalize_language(ext).is_some());
+                            match by_name.or_else(|| detect_language(&code)).or_else(ask_language) {
+                                Some(lang) => lang,
+                                None => {
+                                    show_warning("Skipping a code block with no language");
+                                    continue;
+                                }
+                            }
+                        } else {
+                            lang
+                        };
+                        // `py`, `node`, `shell` and the like show (and run) under one name
+                        let lang = normalize_language(&lang).map_or(lang, |known| known.name().to_string());
+                        if unterminated {
+                            show_warning(&format!("The {} block has no closing fence and may be truncated", lang));
+                        }
+                        if normalize_language(&lang) == Some(Lang::Shell) && !config.allow_dangerous {
+                            if let Some(pattern) = find_dangerous_pattern(&code) {
+                                show_warning(&format!(
+                                    "Refusing to run {} block containing `{}`. Pass --allow-dangerous to override.",
+                                    lang, pattern
+                                ));
+                                continue;
+                            }
+                        }
+                        if ask_each {
+                            print!("\n{}", style(format!("Block {} of {}:", index + 1, total)).bold().cyan());
+                            print!("\n{}", format_response(&format!("```{}\n{}\n```", lang, code.trim_end())));
+                            match confirm_block() {
+                                BlockChoice::Run => {}
+                                BlockChoice::Skip => continue,
+                                BlockChoice::RunAll => ask_each = false,
+                                BlockChoice::Quit => break,
+                            }
+                        }
+                        println!(
+                            "\n{} {} {}{}",
+                            style("Executing").bold().green(),
+                            style(&lang).bold().cyan(),
+                            style("code block").bold().green(),
+                            match &filename {
+                                Some(name) => format!(" {}", style(format!("({}):", name)).bold().green()),
+                                None => style(":").bold().green().to_string(),
+                            }
+                        );
+                        let mut code = code;
+                        let mut result = execute_code_block(&code, &lang, filename.as_deref(), session, config);
+                        print_execution_result(&result, config);
+
+                        let mut attempts = 0;
+                        while let Err(err) = &result {
+                            if !config.auto_fix || !err.is_code_error() || attempts == MAX_FIX_ATTEMPTS {
+                                break;
+                            }
+                            attempts += 1;
+                            let fixed = match request_fix(&code, &lang, &err.to_string(), model, session.runner.as_ref()) {
+                                Ok(fixed) => fixed,
+                                Err(e) => {
+                                    show_warning(&format!("No fix suggested: {}", e));
+                                    break;
+                                }
+                            };
+                            println!(
+                                "\n{}",
+                                style(format!("Suggested fix ({}/{}):", attempts, MAX_FIX_ATTEMPTS)).bold().cyan()
+                            );
+                            print_diff(&code, &fixed);
+                            if !confirm("Run the fixed version?") {
+                                break;
+                            }
+                            code = fixed;
+                            result = execute_code_block(&code, &lang, filename.as_deref(), session, config);
+                            print_execution_result(&result, config);
+                        }
+
+                        ran += 1;
+                        stopped = matches!(result, Err(CodexError::Cancelled));
+                        if let Some(record) = records.get_mut(index) {
+                            record.language = lang;
+                            record.code = code;
+                            record.executed = !config.dry_run;
+                            match result {
+                                Ok(output) => record.result = Some(output).filter(|output| !output.is_empty()),
+                                Err(e) => record.error = Some(e.to_string()),
+                            }
+                        }
+                        if stopped {
+                            break;
+                        }
+                    }
+                    if stopped {
+                        show_warning(&format!("Stopped after running {} of {} code block(s)", ran, total));
+                    }
+                }
+            }
+        }
+        // The interrupt handler already said so
+        Err(CodexError::Cancelled) => {}
+        // Offer to pull a model Ollama doesn't have yet, then ask again
+        Err(CodexError::ModelNotFound(name)) if !raw && io::stdin().is_terminal() => {
+            if confirm(&format!("Model '{}' not found. Pull it now?", name)) {
+                match pull_model(&name, pick_backend(config.backend)) {
+                    Ok(()) => {
+                        show_success(&format!("Pulled {}, asking again", name));
+                        return process_prompt(prompt, config, session);
+                    }
+                    Err(e) => show_error(&format!("Could not pull {}: {}", name, e)),
+                }
+            } else {
+                show_warning(&format!("Pull it later with `ollama pull {}`", name));
+            }
+        }
+        Err(e) => {
+            println!("\n{} {}", style("Error:").bold().red(), style(e).red());
+            println!("{}", style("Please try again or Ctrl+C to exit").dim());
+        }
+    }
+    export_block_records(config, &records);
+}
+
+/// The `--stats` footer: wall time, plus tokens and tokens/s. Without a
+/// count from the server, tokens are estimated from words (about 3 tokens
+/// for every 4 words) and the rate from the wall time.
+fn response_stats(response: &str, elapsed: Duration, eval: Option<EvalCount>) -> String {
+    let secs = elapsed.as_secs_f64();
+    match eval {
+        Some(EvalCount { tokens, duration }) => format!(
+            "{}{:.1} s · {} tokens · {:.1} tokens/s",
+            icon("⏱"),
+            secs,
+            tokens,
+            tokens as f64 / duration.as_secs_f64().max(0.001)
+        ),
+        None => {
+            let tokens = (response.split_whitespace().count() * 4).div_ceil(3);
+            format!(
+                "{}{:.1} s · ~{} tokens (estimated) · ~{:.1} tokens/s",
+                icon("⏱"),
+                secs,
+                tokens,
+                tokens as f64 / secs.max(0.001)
+            )
+        }
+    }
+}
+
+/// The "AI Response:" title that a response starts under
+fn print_response_heading() {
+    print_heading(style(format!("{}AI Response:", icon("🧠"))).bold().cyan());
+    println!();
+}
+
+/// An unexecuted `--export-blocks` record for each code block in `response`
+fn block_records(prompt: &str, response: &str) -> Vec<BlockRecord> {
+    extract_code_blocks(response)
+        .iter()
+        .map(|block| BlockRecord::new(prompt, &block.lang, &block.code))
+        .collect()
+}
+
+/// Append `records` to the `--export-blocks` file, if there is one
+fn export_block_records(config: &ResolvedConfig, records: &[BlockRecord]) {
+    let Some(path) = &config.export_blocks else { return };
+    if records.is_empty() {
+        return;
+    }
+    if let Err(e) = export_blocks(Path::new(path), records) {
+        show_warning(&format!("Could not export code blocks: {}", e));
+    }
+}
+
+fn print_execution_result(result: &Result<String, CodexError>, config: &ResolvedConfig) {
+    match result {
+        // Captured output was echoed while it ran
+        Ok(res) if !res.is_empty() && (!config.capture || config.dry_run) => {
+            print_heading(style("Execution result:").bold().green());
+            println!("{}", res);
+            print_divider();
+        }
+        Ok(_) => (),
+        Err(err) => println!("\n{} {}", style("Execution error:").bold().red(), style(err).red()),
+    }
+}
+
+/// Answers prompts for the session. `OllamaRunner` is the real one; anything
+/// else implementing this (another backend, or a canned stub) can stand in.
+trait ModelRunner {
+    /// Answer `prompt` with `model`, handing each line to `on_line` as it
+    /// arrives, and return the whole response
+    fn run(&self, prompt: &str, model: &str, on_line: &mut dyn FnMut(&str)) -> Result<String, CodexError>;
+
+    /// The token count of the last successful `run`, if the backend gave one
+    fn last_eval(&self) -> Option<EvalCount> {
+        None
+    }
+}
+
+/// Runs prompts through Ollama with `run_model`
+struct OllamaRunner {
+    backend: Backend,
+    options: ModelOptions,
+    retries: u32,
+    last_eval: Cell<Option<EvalCount>>,
+}
+
+impl OllamaRunner {
+    fn new(config: &ResolvedConfig) -> Self {
+        let backend = pick_backend(config.backend);
+        debug!("using the {:?} backend", backend);
+        OllamaRunner {
+            backend,
+            options: ModelOptions::from_config(config),
+            retries: MODEL_RETRIES,
+            last_eval: Cell::new(None),
+        }
+    }
+}
+
+impl ModelRunner for OllamaRunner {
+    fn run(&self, prompt: &str, model: &str, on_line: &mut dyn FnMut(&str)) -> Result<String, CodexError> {
+        let reply = run_model(self.retries, RETRY_BACKOFF, || match self.backend {
+            Backend::Http => stream_model_http(prompt, model, &self.options, &mut *on_line),
+            Backend::Cli => stream_model(prompt, model, &self.options, &mut *on_line),
+        })?;
+        self.last_eval.set(reply.eval);
+        Ok(reply.text)
+    }
+
+    fn last_eval(&self) -> Option<EvalCount> {
+        self.last_eval.get()
+    }
+}
+
+fn print_profiles(current: Option<&str>) {
+    let names = list_profiles();
+    if names.is_empty() {
+        show_warning("No profiles yet. Add [profiles.<name>] sections to ~/.config/codexcli/config.toml");
+        return;
+    }
+    println!("\n{}", style("Profiles:").bold().cyan());
+    for name in names {
+        if current == Some(name.as_str()) {
+            println!("  {} {}", style(&name).bold().green(), style("(active)").dim());
+        } else {
+            println!("  {}", name);
+        }
+    }
+    println!("{}", style("Usage: !profile <name>").dim());
+}
+
+/// Re-resolve the settings with profile `name` and carry them into the
+/// session. Flags given at startup still win over the profile, and display
+/// settings and the workdir stay as they were.
+fn switch_profile(name: &str, args: &Args, config: &mut ResolvedConfig, session: &mut Session) {
+    let mut args = args.clone();
+    args.profile = Some(name.to_string());
+    let mut new = match resolve_config(args) {
+        Ok(new) => new,
+        Err(e) => {
+            show_error(&e.to_string());
+            return;
+        }
+    };
+    // Keep the backend picked at startup unless the profile names one
+    new.backend = new.backend.or(config.backend);
+    if let Err(e) = ensure_model_installed(&new.model, pick_backend(new.backend)) {
+        show_error(&e.to_string());
+        return;
+    }
+    if let Some(host) = &new.ollama_host {
+        env::set_var("OLLAMA_HOST", host);
+    }
+    session.model = new.model.clone();
+    session.runner = Box::new(OllamaRunner::new(&new));
+    session.conversation.system = new.system_prompt.clone();
+    session.log = new.log;
+    session.keep_temp = new.keep_temp;
+    show_success(&format!("Switched to profile '{}' (model {})", name, new.model));
+    *config = new;
+}
+
+/// Run `prompt` on each model in turn, printing every answer as it streams,
+/// then a table comparing time and length. Failures are reported in the
+/// table rather than stopping the run.
+fn compare_models(prompt: &str, models: &[String], runner: &dyn ModelRunner, raw: bool) {
+    let mut rows = Vec::new();
+    for model in models {
+        if raw {
+            println!("## {}", model);
+        } else {
+            print_heading(format!("{}{}", style(icon("🧠")).bold(), style(model).bold().cyan()));
+        }
+        let mut formatter = ResponseFormatter::for_terminal();
+        let spinner = (!raw).then(|| show_spinner(&format!("Waiting for {}...", model)));
+        let started = Instant::now();
+        let result = runner.run(prompt, model, &mut |line| {
+            if let Some(sp) = &spinner {
+                sp.finish_and_clear();
+            }
+            if raw {
+                println!("{}", line);
+            } else if let Some(line) = formatter.format_line(line) {
+                println!("{}", line);
+            }
+        });
+        let elapsed = started.elapsed();
+        if let Some(sp) = spinner {
+            sp.finish_and_clear();
+        }
+        if let Some(rest) = formatter.finish() {
+            println!("{}", rest);
+        }
+
+        let secs = elapsed.as_secs_f64();
+        rows.push(match result {
+            Ok(response) => {
+                let words = response.split_whitespace().count();
+                format!(
+                    "| {} | {:.1} s | {} | {} | {:.1} |",
+                    model,
+                    secs,
+                    words,
+                    response.len(),
+                    words as f64 / secs.max(0.001)
+                )
+            }
+            Err(e) => {
+                show_error(&format!("{}: {}", model, e));
+                let reason = e.to_string().lines().next().unwrap_or_default().replace('|', "/");
+                format!("| {} | {:.1} s | - | - | failed: {} |", model, secs, reason)
+            }
+        });
+    }
+
+    let table = format!(
+        "| Model | Time | Words | Chars | Words/s |\n|:--|--:|--:|--:|--:|\n{}",
+        rows.join("\n")
+    );
+    println!();
+    if raw {
+        println!("{}", table);
     } else {
-        cmd.current_dir("react-app");
+        print!("{}", format_response(&table));
+    }
+}
+
+/// How many times `--auto-fix` asks the model to repair one code block
+const MAX_FIX_ATTEMPTS: usize = 3;
+
+/// Send failing code and its error back to the model and pull the
+/// corrected block out of the reply
+fn request_fix(code: &str, lang: &str, error: &str, model: &str, runner: &dyn ModelRunner) -> Result<String, CodexError> {
+    let prompt = format!(
+        "This {lang} code failed.\n\n```{lang}\n{code}\n```\n\nError:\n{error}\n\n\
+         Reply with the corrected program in a single ```{lang} code block.",
+        lang = lang,
+        code = code.trim_end(),
+        error = error.trim()
+    );
+
+    let spinner = show_spinner("Asking for a fix...");
+    let reply = runner.run(&prompt, model, &mut |_| {});
+    spinner.finish_and_clear();
+
+    let fixed = extract_code_blocks(&reply?)
+        .into_iter()
+        .next()
+        .map(|block| block.code)
+        .ok_or_else(|| CodexError::from("the reply had no code block"))?;
+    if fixed.trim() == code.trim() {
+        return Err("the model returned the same code".into());
+    }
+    Ok(fixed)
+}
+
+/// Line diff between two versions of a code block
+fn print_diff(old: &str, new: &str) {
+    for change in TextDiff::from_lines(old, new).iter_all_changes() {
+        let line = change.to_string_lossy();
+        let line = line.trim_end_matches('\n');
+        match change.tag() {
+            ChangeTag::Delete => println!("{}", style(format!("-{}", line)).red()),
+            ChangeTag::Insert => println!("{}", style(format!("+{}", line)).green()),
+            ChangeTag::Equal => println!("{}", style(format!(" {}", line)).dim()),
+        }
+    }
+}
+
+/// Whether `program` can be found on PATH. Lookups are cached, since the
+/// answer won't change during a session.
+fn on_path(program: &str) -> bool {
+    static FOUND: OnceLock<Mutex<HashMap<String, bool>>> = OnceLock::new();
+    let mut found = FOUND.get_or_init(Default::default).lock().unwrap_or_else(|e| e.into_inner());
+    *found
+        .entry(program.to_string())
+        .or_insert_with(|| which::which(program).is_ok())
+}
+
+/// The program to run instead of a missing `program`, given which programs
+/// `found` says are installed
+fn program_fix(program: &str, found: &dyn Fn(&str) -> bool) -> Option<&'static str> {
+    Some(match program {
+        "python" | "python3" if cfg!(windows) => "py",
+        "pip" | "pip3" if cfg!(windows) => "py -m pip",
+        "python" if !found("python") && found("python3") => "python3",
+        "pip" if !found("pip") && found("pip3") => "pip3",
+        "pip" | "pip3" if !found(program) && found("python3") => "python3 -m pip",
+        "npm" if !found("npm") && found("npx") => "npx",
+        _ => return None,
+    })
+}
+
+/// Rewrite a failed command to use an equivalent program that exists, e.g.
+/// `python3` on systems without a bare `python`. Only the program word
+/// changes, so quoting, pipes and redirects after it stay as typed. Errors
+/// when no fix applies.
+fn fix_command(command: &str) -> Result<String, CodexError> {
+    fix_command_with(command, &on_path)
+}
+
+fn fix_command_with(command: &str, found: &dyn Fn(&str) -> bool) -> Result<String, CodexError> {
+    let command = command.trim_start();
+    let (program, rest) = command.split_at(command.find(char::is_whitespace).unwrap_or(command.len()));
+    if program.is_empty() {
+        return Err("Empty command".into());
+    }
+    let replacement = program_fix(program, found).ok_or_else(|| format!("No known fix for {}", program))?;
+    Ok(format!("{}{}", replacement, rest))
+}
+
+/// The prompt for a single non-interactive run: the positional argument,
+/// piped stdin, or both (argument first, then the piped text)
+fn one_shot_prompt(positional: Option<String>) -> Option<String> {
+    if io::stdin().is_terminal() {
+        return positional;
+    }
+
+    let mut piped = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut piped) {
+        show_warning(&format!("Could not read piped input: {}", e));
+    }
+    match (positional, piped.trim()) {
+        (Some(prompt), "") => Some(prompt),
+        (Some(prompt), piped) => Some(format!("{}\n\n{}", prompt, piped)),
+        (None, "") => None,
+        (None, piped) => Some(piped.to_string()),
+    }
+}
+
+fn main() {
+    init_colors();
+    let mut args = Args::parse();
+    init_logging(args.verbose);
+    install_interrupt_handler();
+    if let Some(path) = args.system_prompt_file.take() {
+        match fs::read_to_string(&path) {
+            Ok(text) => args.system_prompt = Some(text.trim().to_string()),
+            Err(e) => {
+                show_error(&format!("Could not read system prompt file {}: {}", path, e));
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut positional = args.prompt.take();
+    if let Some(path) = args.prompt_file.take() {
+        match fs::read_to_string(&path) {
+            Ok(text) if !text.trim().is_empty() => positional = Some(text.trim().to_string()),
+            Ok(_) => {
+                show_error(&format!("Prompt file {} is empty", path));
+                std::process::exit(1);
+            }
+            Err(e) => {
+                show_error(&format!("Could not read prompt file {}: {}", path, e));
+                std::process::exit(1);
+            }
+        }
+    }
+    let list_models = args.list_models;
+    let replay = args.replay.take();
+    let compare = std::mem::take(&mut args.compare);
+    let template = args.template.take();
+    let mut config = match resolve_config(args.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            show_error(&e.to_string());
+            std::process::exit(1);
+        }
+    };
+    init_appearance(&config);
+    // Every ollama call (the CLI, and the HTTP API via `ollama_url`) finds
+    // the server through this
+    if let Some(host) = &config.ollama_host {
+        env::set_var("OLLAMA_HOST", host);
+    }
+
+    if let Some(path) = replay {
+        if let Err(e) = replay_transcript(Path::new(&path), config.format) {
+            show_error(&format!("Could not replay transcript: {}", e));
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Settle the backend once, so listing and pulling models go the same way
+    // as prompts
+    let chose_backend = config.backend.is_some();
+    let backend = pick_backend(config.backend);
+    config.backend = Some(backend);
+
+    // Scripts using --raw get the plain error from the first model call, and
+    // the HTTP backend doesn't need the CLI at all
+    if !config.raw && backend == Backend::Cli && which::which("ollama").is_err() {
+        show_error("Ollama is not installed or not on PATH. CodexCLI needs it to run models.");
+        println!("\nInstall it with:\n\n    {}\n", style(ollama_install_hint()).bold());
+        println!("{}", style("Then start it (`ollama serve`) and run codex_cli again.").dim());
+        std::process::exit(1);
+    }
+    if backend == Backend::Cli && !ModelOptions::from_config(&config).is_default() {
+        if chose_backend {
+            show_error(CLI_OPTIONS_ERROR);
+        } else {
+            show_error(&format!("{} (no Ollama server answered at {})", CLI_OPTIONS_ERROR, ollama_url()));
+        }
+        std::process::exit(1);
+    }
+
+    if list_models {
+        if let Err(e) = print_models(&config.model, backend) {
+            show_error(&format!("Could not list models: {}", e));
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut one_shot = one_shot_prompt(positional);
+    if let Some(name) = &template {
+        let Some(input) = &one_shot else {
+            show_error("--template needs a prompt to fill it with, e.g. codex_cli --template refactor \"...\"");
+            std::process::exit(1);
+        };
+        match expand_template(name, input) {
+            Ok(text) => one_shot = Some(text),
+            Err(e) => {
+                show_error(&e.to_string());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if config.output_file.is_some() && one_shot.is_none() {
+        show_error("--output-file needs a prompt, e.g. codex_cli --output-file answer.md \"...\"");
+        std::process::exit(1);
+    }
+
+    if !compare.is_empty() {
+        let Some(prompt) = one_shot else {
+            show_error("--compare needs a prompt, e.g. codex_cli --compare llama3.2,mistral \"...\"");
+            std::process::exit(1);
+        };
+        let payload = Conversation::new(false, 0, config.system_prompt.clone()).payload(&prompt);
+        compare_models(&payload, &compare, &OllamaRunner::new(&config), config.raw);
+        return;
+    }
+
+    if !config.raw && one_shot.is_none() {
+        if !config.no_banner {
+            print_banner(config.banner_font.as_deref());
+        }
+        println!("{}", style("Type your prompt and hit Enter; !quit or Ctrl+D to exit.").dim());
+        println!("{}", style("For system commands, prefix with ! (e.g. !ls)").dim());
+        print_divider();
+    }
+
+    match list_installed_models(backend) {
+        Ok(installed) if !model_is_installed(&config.model, &installed) => {
+            // At a terminal, offer the same pull as !switch; scripts get the
+            // command to run instead of a question nobody will answer
+            if !config.raw && io::stdin().is_terminal() {
+                let question = format!("Model '{}' is not installed. Pull it now?", config.model);
+                if confirm(&question) {
+                    match pull_model(&config.model, backend) {
+                        Ok(()) => show_success(&format!("Pulled {}", config.model)),
+                        Err(e) => {
+                            show_error(&format!("Could not pull '{}': {}", config.model, e));
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    show_error(&format!("Model '{}' is not installed", config.model));
+                    std::process::exit(1);
+                }
+            } else {
+                show_error(&format!(
+                    "Model '{}' is not installed. Run `ollama pull {}` or pick one of: {}",
+                    config.model,
+                    config.model,
+                    installed.join(", ")
+                ));
+                std::process::exit(1);
+            }
+        }
+        Ok(_) => (),
+        Err(e) => show_warning(&format!("Could not verify model '{}': {}", config.model, e)),
+    }
+
+    let workspace = match Workspace::resolve(config.workdir.as_deref(), config.allow_unsafe_workdir) {
+        Ok(workspace) => workspace,
+        Err(e) => {
+            show_error(&e.to_string());
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = workspace.check_writable() {
+        // An explicit --workdir that can't be written to is always a mistake;
+        // an unwritable cwd only matters once code is executed
+        if config.workdir.is_some() {
+            show_error(&format!("{}. Choose a different --workdir.", e));
+            std::process::exit(1);
+        }
+        show_warning(&format!("{}. Code blocks will run in a temp dir; use --workdir to choose where.", e));
+    }
+
+    if config.reinstall_venv {
+        match reset_python_environment(workspace.path()) {
+            Ok(()) => show_success("Python environment will be rebuilt on the next Python block"),
+            Err(e) => show_warning(&format!("Could not remove the old venv: {}", e)),
+        }
+    }
+
+    let mut session = Session::new(&config, workspace);
+    for pattern in &config.context_files {
+        if let Err(e) = add_context_files(pattern, &mut session.conversation.files) {
+            show_warning(&e.to_string());
+        }
+    }
+    if !config.raw && !session.conversation.files.is_empty() {
+        print_context_files(&session.conversation.files);
+    }
+
+    if let Some(prompt) = one_shot {
+        process_prompt(&prompt, &config, &mut session);
+        if let Some(path) = &config.output_file {
+            let Some(response) = &session.last_response else {
+                show_error(&format!("No response to write to {}", path));
+                std::process::exit(1);
+            };
+            if let Err(e) = write_output_file(path, &prompt, &session.model, response, config.format) {
+                show_error(&format!("Could not write {}: {}", path, e));
+                std::process::exit(1);
+            }
+        }
+        // Exiting would take any server we just started down with us
+        if !session.servers.is_empty() {
+            println!("{}", style("Serving until interrupted. Press Ctrl+C to stop.").dim());
+            for server in &mut session.servers {
+                let _ = server.child.wait();
+            }
+        }
+        return;
+    }
+
+    let mut editor = match create_editor() {
+        Ok(editor) => editor,
+        Err(e) => {
+            show_error(&format!("Failed to initialize line editor: {}", e));
+            std::process::exit(1);
+        }
+    };
+
+    while let Some(prompt) = get_user_input(&mut editor, config.format == OutputFormat::Json) {
+        if prompt.is_empty() {
+            continue;
+        }
+        if matches!(prompt.trim(), "!quit" | "!exit") {
+            break;
+        }
+        // Switching profiles replaces the config, so it's handled here
+        // rather than in `process_prompt`
+        if let Some(name) = prompt
+            .trim()
+            .strip_prefix("!profile")
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        {
+            match name.trim() {
+                "" => print_profiles(config.profile.as_deref()),
+                name => switch_profile(name, &args, &mut config, &mut session),
+            }
+            continue;
+        }
+        process_prompt(&prompt, &config, &mut session);
     }
-    
-    let _status = cmd
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map_err(|e| e.to_string())?;
 
-    Ok("React development server started. Press Ctrl+C to stop.".to_string())
+    if !config.raw {
+        println!("\n{}", style(format!("{}Goodbye!", icon("👋"))).dim());
+    }
+    // Stop servers and remove temp pages before a Ctrl-C exit skips it
+    drop(session);
+    if QUIT_REQUESTED.load(Ordering::SeqCst) {
+        std::process::exit(130);
+    }
 }
 
-fn start_local_server(port: u16, workdir: Option<&str>) -> Result<String, String> {
-    println!("{}", style("Starting local server...").bold().yellow());
-    
-    // Try Python's http.server first
-    let mut cmd = Command::new("python");
-    cmd.args(&["-m", "http.server", &port.to_string()]);
-    
-    if let Some(dir) = workdir {
-        cmd.current_dir(dir);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    fn test_config(flags: &[&str]) -> ResolvedConfig {
+        let args = Args::parse_from(["codexcli", "--backend", "cli"].iter().chain(flags));
+        config::merge_config(args, Default::default(), Default::default())
     }
-    
-    let _status = cmd
-        .stdin(Stdio::inherit())
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .map_err(|e| e.to_string())?;
 
-    Ok(format!("Local server started on port {}. Press Ctrl+C to stop.", port))
-}
+    fn test_session(config: &ResolvedConfig, dir: &Path) -> Session {
+        let workspace = Workspace::resolve(Some(&dir.to_string_lossy()), false).unwrap();
+        Session::new(config, workspace)
+    }
 
-fn execute_code_block(code: &str, language: &str, workdir: Option<&str>) -> Result<String, String> {
-    // Check for special commands
-    if code.trim() == "create-react-app" {
-        return setup_react_environment(workdir)
-            .and_then(|_| Ok("React application created successfully. Use 'npm start' to run the development server.".to_string()));
+    /// Answers with canned results in order, retrying through `run_model`
+    /// like `OllamaRunner`, and counts the requests it gets
+    struct MockRunner {
+        replies: RefCell<VecDeque<Result<String, CodexError>>>,
+        retries: u32,
+        calls: Rc<Cell<usize>>,
     }
-    
-    if code.trim() == "npm start" {
-        return start_react_server(workdir);
+
+    impl ModelRunner for MockRunner {
+        fn run(&self, _prompt: &str, _model: &str, on_line: &mut dyn FnMut(&str)) -> Result<String, CodexError> {
+            run_model(self.retries, Duration::ZERO, || {
+                self.calls.set(self.calls.get() + 1);
+                let reply = self.replies.borrow_mut().pop_front().unwrap_or_else(|| Err("no reply left".into()))?;
+                for line in reply.lines() {
+                    on_line(line);
+                }
+                Ok(reply)
+            })
+        }
     }
-    
-    if code.trim().starts_with("start-server") {
-        let port = code
-            .split_whitespace()
-            .nth(1)
-            .and_then(|p| p.parse::<u16>().ok())
-            .unwrap_or(8000);
-        return start_local_server(port, workdir);
-    }
-
-    let ext = match language.to_lowercase().as_str() {
-        "python" | "py" => "py",
-        "javascript" | "js" => "js",
-        "typescript" | "ts" => "ts",
-        "rust" | "rs" => "rs",
-        "bash" | "sh" => "sh",
-        "html" => "html",
-        _ => return Err(format!("Unsupported language: {}", language)),
-    };
 
-    // Create working directory if specified
-    if let Some(dir) = workdir {
-        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
-        env::set_current_dir(dir).map_err(|e| e.to_string())?;
+    /// A session whose model gives `replies`, and a count of the requests
+    /// made to it
+    fn mock_session(
+        config: &ResolvedConfig,
+        dir: &Path,
+        retries: u32,
+        replies: Vec<Result<String, CodexError>>,
+    ) -> (Session, Rc<Cell<usize>>) {
+        let mut session = test_session(config, dir);
+        let calls = Rc::new(Cell::new(0));
+        session.runner = Box::new(MockRunner { replies: RefCell::new(replies.into()), retries, calls: calls.clone() });
+        (session, calls)
     }
 
-    let fname = format!("temp_code.{}", ext);
-    File::create(&fname)
-        .and_then(|mut f| f.write_all(code.as_bytes()))
-        .map_err(|e| e.to_string())?;
+    #[test]
+    fn extracts_a_tagged_block() {
+        let blocks = extract_code_blocks("Here:\n```python\nprint(1)\n```\nDone.");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].lang, "python");
+        assert_eq!(blocks[0].code, "print(1)\n");
+        assert!(!blocks[0].unterminated);
+    }
 
-    let result = || -> Result<String, String> {
-        match ext {
-            "py" => {
-                // Setup Python environment
-                setup_python_environment()?;
-                
-                let python_path = if cfg!(windows) {
-                    "venv\\Scripts\\python.exe"
-                } else {
-                    "venv/bin/python"
-                };
+    #[test]
+    fn extracts_a_block_without_a_language() {
+        let blocks = extract_code_blocks("```\necho hi\n```");
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].lang, "");
+        assert_eq!(blocks[0].code, "echo hi\n");
+    }
 
-                // First try non-interactive mode
-                let out = Command::new(python_path)
-                    .arg(&fname)
-                    .output()
-                    .map_err(|e| e.to_string())?;
+    #[test]
+    fn extracts_consecutive_blocks() {
+        let blocks = extract_code_blocks("```js\nconsole.log(1)\n```\n```rust\nfn main() {}\n```\n");
+        let langs: Vec<&str> = blocks.iter().map(|b| b.lang.as_str()).collect();
+        assert_eq!(langs, ["js", "rust"]);
+        assert_eq!(blocks[1].code, "fn main() {}\n");
+    }
 
-                if out.status.success() {
-                    return Ok(String::from_utf8_lossy(&out.stdout).to_string());
-                }
+    #[test]
+    fn keeps_a_trailing_unterminated_block() {
+        let blocks = extract_code_blocks("```sh\necho done\n```\n```python\nfor i in range(3):\n    print(i)");
+        assert_eq!(blocks.len(), 2);
+        assert!(!blocks[0].unterminated);
+        assert!(blocks[1].unterminated);
+        assert_eq!(blocks[1].lang, "python");
+        assert_eq!(blocks[1].code, "for i in range(3):\n    print(i)\n");
+    }
 
-                let err = String::from_utf8_lossy(&out.stderr).to_string();
+    #[test]
+    fn strips_the_fence_indent_from_list_items() {
+        let response = "- Install it\n- Run this:\n\n    ```python\n    def greet():\n        print(\"hi\")\n\n    greet()\n    ```\n";
+        let blocks = extract_code_blocks(response);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].lang, "python");
+        assert_eq!(blocks[0].code, "def greet():\n    print(\"hi\")\n\ngreet()\n");
+    }
 
-                // Handle missing modules
-                if err.contains("ModuleNotFoundError") {
-                    return handle_python_error(&err, code);
-                }
+    #[test]
+    fn strip_indent_keeps_deeper_and_shallower_lines() {
+        assert_eq!(strip_indent("        return x", 4), "    return x");
+        assert_eq!(strip_indent("  x = 1", 4), "x = 1");
+        assert_eq!(strip_indent("\tx = 1", 4), "x = 1");
+        assert_eq!(strip_indent("x = 1", 0), "x = 1");
+    }
 
-                // For any input-related errors, switch to interactive mode
-                if err.contains("input(") || err.contains("EOF") || err.contains("EOFError") {
-                    println!(
-                        "{}",
-                        style("\nSwitching to interactive mode. Press Ctrl+C when done.").bold().yellow()
-                    );
-                    
-                    let mut child = Command::new(python_path)
-                        .arg(&fname)
-                        .stdin(Stdio::inherit())
-                        .stdout(Stdio::inherit())
-                        .stderr(Stdio::inherit())
-                        .spawn()
-                        .map_err(|e| e.to_string())?;
+    #[test]
+    fn running_a_block_leaves_the_cwd_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--capture"]);
+        let mut session = test_session(&config, dir.path());
+        let before = env::current_dir().unwrap();
 
-                    let status = child.wait().map_err(|e| e.to_string())?;
-                    if status.success() {
-                        Ok(String::new())
-                    } else {
-                        Err(format!("Python exited with status: {}", status))
-                    }
-                } else {
-                    Err(err)
-                }
-            }
-            "js" => {
-                // Setup Node.js environment
-                setup_node_environment()?;
-                
-                let out = Command::new("node")
-                    .arg(&fname)
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .status()
-                    .map_err(|e| e.to_string())?;
-                
-                if out.success() {
-                    Ok(String::new())
-                } else {
-                    let err = handle_node_error("", code)?;
-                    if err.is_empty() {
-                        Ok(String::new())
-                    } else {
-                        Err(format!("Node.js exited with status: {}", out))
-                    }
-                }
-            }
-            "ts" => {
-                setup_node_environment()?;
-                install_node_package("typescript")?;
-                install_node_package("ts-node")?;
-                
-                let out = Command::new("npx")
-                    .args(&["ts-node", &fname])
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .status()
-                    .map_err(|e| e.to_string())?;
-
-                if out.success() {
-                    Ok(String::new())
-                } else {
-                    Err(format!("TypeScript execution failed with status: {}", out))
-                }
-            }
-            "rs" => {
-                let out = Command::new("rustc")
-                    .arg(&fname)
-                    .output()
-                    .map_err(|e| e.to_string())?;
-                
-                if !out.status.success() {
-                    return Err(String::from_utf8_lossy(&out.stderr).to_string());
-                }
+        let output = execute_code_block("pwd > where.txt\necho ran", "bash", None, &mut session, &config).unwrap();
+        assert!(output.contains("ran"), "{}", output);
+        assert_eq!(env::current_dir().unwrap(), before);
+        let ran_in = fs::read_to_string(dir.path().join("where.txt")).unwrap();
+        assert_eq!(Path::new(ran_in.trim()), session.workspace.path());
+    }
 
-                let binary = if cfg!(windows) {
-                    "temp_code.exe"
-                } else {
-                    "./temp_code"
-                };
+    #[test]
+    fn a_failing_block_leaves_the_cwd_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--capture"]);
+        let mut session = test_session(&config, dir.path());
+        let before = env::current_dir().unwrap();
 
-                let status = Command::new(binary)
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .status()
-                    .map_err(|e| e.to_string())?;
+        assert!(execute_code_block("exit 3", "bash", None, &mut session, &config).is_err());
+        assert_eq!(env::current_dir().unwrap(), before);
+    }
 
-                if status.success() {
-                    Ok(String::new())
-                } else {
-                    Err(format!("Rust program exited with status: {}", status))
-                }
+    #[test]
+    fn run_model_retries_until_the_daemon_answers() {
+        let mut calls = 0;
+        let result = run_model(3, Duration::ZERO, || {
+            calls += 1;
+            if calls < 3 {
+                Err(CodexError::ModelUnavailable("connection refused".into()))
+            } else {
+                Ok("hello")
             }
-            "sh" => {
-                let mut cmd = if cfg!(windows) {
-                    let mut c = Command::new("wsl");
-                    c.args(&["bash", "-c", &format!("bash {}", fname)]);
-                    c
-                } else {
-                    let mut c = Command::new("bash");
-                    c.arg(&fname);
-                    c
-                };
-
-                let status = cmd
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .status()
-                    .map_err(|e| e.to_string())?;
+        });
+        assert_eq!(result.unwrap(), "hello");
+        assert_eq!(calls, 3);
+    }
 
-                if status.success() {
-                    Ok(String::new())
-                } else {
-                    Err(format!("Bash script exited with status: {}", status))
-                }
-            }
-            "html" => {
-                println!("{}", style("Opening HTML in default browser...").bold().yellow());
-                let browser_cmd = if cfg!(windows) {
-                    Command::new("cmd")
-                        .args(&["/C", "start", &fname])
-                        .status()
-                        .map_err(|e| e.to_string())?
-                } else if cfg!(target_os = "macos") {
-                    Command::new("open")
-                        .arg(&fname)
-                        .status()
-                        .map_err(|e| e.to_string())?
-                } else {
-                    Command::new("xdg-open")
-                        .arg(&fname)
-                        .status()
-                        .map_err(|e| e.to_string())?
-                };
+    #[test]
+    fn run_model_gives_up_after_the_retries() {
+        let mut calls = 0;
+        let result: Result<(), _> = run_model(2, Duration::ZERO, || {
+            calls += 1;
+            Err(CodexError::ModelUnavailable("connection refused".into()))
+        });
+        assert_eq!(calls, 3);
+        let err = result.unwrap_err();
+        assert!(matches!(err, CodexError::ModelUnavailable(_)));
+        let message = err.to_string();
+        assert!(message.starts_with("connection refused"), "{}", message);
+        assert!(message.contains("after 3 attempts"), "{}", message);
+        assert!(message.contains("ollama serve"), "{}", message);
+    }
 
-                if browser_cmd.success() {
-                    Ok(format!("HTML file opened in browser: {}", fname))
-                } else {
-                    Err("Failed to open HTML file in browser".to_string())
-                }
-            }
-            _ => Err(format!("Unsupported language: {}", ext)),
-        }
-    }();
+    #[test]
+    fn run_model_does_not_retry_other_errors() {
+        let mut calls = 0;
+        let result: Result<(), _> = run_model(3, Duration::ZERO, || {
+            calls += 1;
+            Err(CodexError::ModelNotFound("llama9".into()))
+        });
+        assert_eq!(calls, 1);
+        assert!(matches!(result, Err(CodexError::ModelNotFound(_))));
+    }
 
-    // Clean up
-    if ext != "html" {  // Don't delete HTML files immediately as they're being viewed
-        let _ = std::fs::remove_file(&fname);
+    fn code_style(text: &str) -> String {
+        Style::new().cyan().bright().apply_to(text).to_string()
     }
-    if ext == "rs" {
-        let _ = std::fs::remove_file(if cfg!(windows) { "temp_code.exe" } else { "temp_code" });
+
+    #[test]
+    fn styles_only_the_inline_code_span() {
+        console::set_colors_enabled(true);
+        let white = Style::new().white();
+        assert_eq!(
+            style_inline_code("use the `foo()` method", &white),
+            format!("{}{}{}", white.apply_to("use the "), code_style("`foo()`"), white.apply_to(" method"))
+        );
+        assert_eq!(
+            style_inline_code("`a` and `b`", &white),
+            format!(
+                "{}{}{}{}{}",
+                white.apply_to(""),
+                code_style("`a`"),
+                white.apply_to(" and "),
+                code_style("`b`"),
+                white.apply_to("")
+            )
+        );
     }
 
-    // Reset working directory
-    if workdir.is_some() {
-        env::set_current_dir("..").map_err(|e| e.to_string())?;
+    #[test]
+    fn leaves_an_unmatched_backtick_plain() {
+        console::set_colors_enabled(true);
+        let white = Style::new().white();
+        assert_eq!(
+            style_inline_code("run `ls and see", &white),
+            format!("{}{}", white.apply_to("run "), white.apply_to("`ls and see"))
+        );
+        assert_eq!(
+            style_inline_code("`x` then `y", &white),
+            format!("{}{}{}{}", white.apply_to(""), code_style("`x`"), white.apply_to(" then "), white.apply_to("`y"))
+        );
     }
 
-    result
-}
+    #[test]
+    fn backticks_inside_a_fence_are_code_not_spans() {
+        console::set_colors_enabled(true);
+        let mut formatter = ResponseFormatter::default();
+        assert_eq!(formatter.format_line("```").unwrap(), style("```").cyan().to_string());
+        assert_eq!(formatter.format_line("now=`date`").unwrap(), style("now=`date`").cyan().to_string());
+        formatter.format_line("```");
+        let prose = formatter.format_line("then `echo $now`").unwrap();
+        assert!(prose.contains(&code_style("`echo $now`")), "{:?}", prose);
+    }
 
-fn process_prompt(prompt: &str, raw: bool, workdir: Option<&str>) {
-    if prompt.starts_with('!') {
-        let c = &prompt[1..].trim();
-        if !raw {
-            println!("{} {}", style("Executing command:").bold().yellow(), style(c).white());
+    #[test]
+    fn maps_import_names_to_pip_names() {
+        for (import, package) in [
+            ("sklearn", "scikit-learn"),
+            ("yaml", "PyYAML"),
+            ("cv2", "opencv-python"),
+            ("PIL", "Pillow"),
+            ("bs4", "beautifulsoup4"),
+            ("dotenv", "python-dotenv"),
+        ] {
+            assert_eq!(resolve_pip_name(import), package);
         }
-        match execute_command(c) {
-            Ok(o) => {
-                if !raw {
-                    println!("\n{}{}", style("Command output:\n").bold().green(), style("─────────────────────────────\n").dim());
-                    println!("{}", o);
-                    println!("{}", style("─────────────────────────────").dim());
-                } else {
-                    println!("{}", o);
-                }
-            }
-            Err(e) => {
-                show_error(&e);
-                show_error_recovery("Attempting to fix the command...");
-                // Try to fix common command issues
-                if let Ok(fixed) = fix_command(c) {
-                    show_warning(&format!("Trying fixed command: {}", fixed));
-                    match execute_command(&fixed) {
-                        Ok(o) => {
-                            show_success("Command fixed and executed successfully");
-                            println!("{}", o);
-                        }
-                        Err(e) => show_error(&e),
-                    }
-                }
-            }
+    }
+
+    #[test]
+    fn unknown_imports_are_their_own_pip_name() {
+        assert_eq!(resolve_pip_name("requests"), "requests");
+        // Lookups are exact, as import names are case-sensitive
+        assert_eq!(resolve_pip_name("pil"), "pil");
+    }
+
+    #[test]
+    fn finds_the_top_level_missing_module() {
+        let stderr = "Traceback (most recent call last):\n  File \"x.py\", line 1\nModuleNotFoundError: No module named 'sklearn.linear_model'\n";
+        assert_eq!(missing_python_module(stderr).map(resolve_pip_name), Some("scikit-learn"));
+        assert_eq!(missing_python_module("NameError: name 'x' is not defined"), None);
+    }
+
+    #[test]
+    fn plain_read_only_commands_run_without_asking() {
+        for command in ["ls -la", "git status", "echo \"hello world\"", "cat 'my file.txt'", "find . -name '*.rs'"] {
+            assert!(!is_mutating_command(command), "{}", command);
         }
-        return;
     }
 
-    // Check for special commands in the prompt
-    if prompt.trim() == "create-react-app" {
-        match setup_react_environment(workdir) {
-            Ok(_) => println!("\n{}", style("React application created successfully. Use 'npm start' to run the development server.").bold().green()),
-            Err(e) => println!("\n{} {}", style("Error:").bold().red(), style(e).red()),
+    #[test]
+    fn mutating_programs_ask() {
+        for command in ["rm x", "sudo rm -rf build", "/bin/rm x", "git push origin main", "mkfs.ext4 /dev/sdb1", "find . -delete"] {
+            assert!(is_mutating_command(command), "{}", command);
         }
-        return;
     }
 
-    if prompt.trim() == "npm start" {
-        match start_react_server(workdir) {
-            Ok(msg) => println!("\n{}", style(msg).bold().green()),
-            Err(e) => println!("\n{} {}", style("Error:").bold().red(), style(e).red()),
+    #[test]
+    fn chained_and_substituted_commands_ask() {
+        for command in [
+            "echo hi; rm /tmp/victim",
+            "a&&rm x",
+            "true || rm x",
+            "ls | xargs rm",
+            "echo $(rm x)",
+            "echo `rm x`",
+            "echo hi\nrm x",
+            "echo hi > important.txt",
+            "sleep 1 & rm x",
+        ] {
+            assert!(is_mutating_command(command), "{:?}", command);
         }
-        return;
     }
 
-    if prompt.trim().starts_with("start-server") {
-        let port = prompt
-            .split_whitespace()
-            .nth(1)
-            .and_then(|p| p.parse::<u16>().ok())
-            .unwrap_or(8000);
-        match start_local_server(port, workdir) {
-            Ok(msg) => println!("\n{}", style(msg).bold().green()),
-            Err(e) => println!("\n{} {}", style("Error:").bold().red(), style(e).red()),
+    #[test]
+    fn commands_run_through_a_shell_or_wrapper_ask() {
+        for command in ["sh -c 'rm x'", "bash -c \"rm x\"", "eval rm x", "env rm x", "xargs rm"] {
+            assert!(is_mutating_command(command), "{}", command);
         }
-        return;
     }
 
-    if !raw {
-        println!("{} {}", style("🤖 Prompt:").bold().cyan(), style(prompt).white());
-        println!();
+    #[test]
+    fn unparsable_commands_ask() {
+        assert!(is_mutating_command("echo \"unbalanced"));
     }
 
-    let spinner = if raw { None } else { Some(show_spinner()) };
-    let ai = cmd!("ollama", "run", "llama3.2")
-        .stdin_bytes(prompt)
-        .read();
-    if let Some(sp) = spinner {
-        sp.finish_and_clear();
+    #[test]
+    fn parses_quoted_arguments() {
+        assert_eq!(parse_command("echo \"hello world\"").unwrap(), ["echo", "hello world"]);
+        assert_eq!(parse_command("cat 'my notes.txt' other.txt").unwrap(), ["cat", "my notes.txt", "other.txt"]);
     }
 
-    match ai {
-        Ok(output) => {
-            if !raw {
-                println!("\n{}{}", style("🧠 AI Response:\n").bold().cyan(), style("─────────────────────────────\n").dim());
-                println!("{}", format_response(&output));
-                println!("{}", style("─────────────────────────────").dim());
+    #[test]
+    fn parses_escaped_spaces() {
+        assert_eq!(parse_command("ls My\\ Documents").unwrap(), ["ls", "My Documents"]);
+    }
 
-                let blocks = extract_code_blocks(&output);
-                if !blocks.is_empty() {
-                    println!("\n{} (y/n)", style("Found code blocks. Execute them?").bold().yellow());
-                    let mut ans = String::new();
-                    io::stdin().read_line(&mut ans).unwrap();
-                    if ans.trim().eq_ignore_ascii_case("y") {
-                        for (lang, code) in blocks {
-                            println!(
-                                "\n{} {} {}",
-                                style("Executing").bold().green(),
-                                style(&lang).bold().cyan(),
-                                style("code block:").bold().green()
-                            );
-                            match execute_code_block(&code, &lang, workdir) {
-                                Ok(res) => {
-                                    if !res.is_empty() {
-                                        println!(
-                                            "\n{}{}",
-                                            style("Execution result:\n").bold().green(),
-                                            style("─────────────────────────────").dim()
-                                        );
-                                        println!("{}", res);
-                                        println!("{}", style("─────────────────────────────").dim());
-                                    }
-                                }
-                                Err(err) => println!("\n{} {}", style("Execution error:").bold().red(), style(err).red()),
-                            }
-                        }
-                    }
-                }
-            } else {
-                println!("{}", output);
-            }
+    #[test]
+    fn single_quotes_are_literal_but_double_quotes_escape() {
+        assert_eq!(parse_command(r#"echo '$HOME \"x\"'"#).unwrap(), ["echo", r#"$HOME \"x\""#]);
+        assert_eq!(parse_command(r#"echo "say \"hi\"" 'it''s'"#).unwrap(), ["echo", r#"say "hi""#, "its"]);
+    }
+
+    #[test]
+    fn unbalanced_quotes_are_an_error() {
+        let err = parse_command("echo \"oops").unwrap_err();
+        assert!(err.to_string().contains("Unbalanced quotes"), "{}", err);
+        assert!(parse_command("echo 'oops").is_err());
+    }
+
+    #[test]
+    fn shell_commands_pass_through_whole() {
+        assert_eq!(
+            command_argv("ls | wc -l", Some("/bin/bash")).unwrap(),
+            ["/bin/bash", "-c", "ls | wc -l"]
+        );
+        assert_eq!(command_argv("dir", Some("cmd")).unwrap(), ["cmd", "/C", "dir"]);
+        assert_eq!(command_argv("echo 'a b'", None).unwrap(), ["echo", "a b"]);
+    }
+
+    #[test]
+    fn process_prompt_records_an_answer() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--execute", "never"]);
+        let (mut session, calls) = mock_session(&config, dir.path(), 0, vec![Ok("Hello there.\n".into())]);
+
+        process_prompt("hi", &config, &mut session);
+        assert_eq!(calls.get(), 1);
+        assert_eq!(session.last_response.as_deref(), Some("Hello there.\n"));
+        assert_eq!(session.conversation.turns.len(), 2);
+        assert_eq!(session.last_prompt.as_deref(), Some("hi"));
+    }
+
+    #[test]
+    fn process_prompt_runs_a_code_block_from_the_answer() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--allow-exec", "--execute", "always", "--capture"]);
+        let answer = "Run this:\n```bash\necho made > made.txt\n```\n";
+        let (mut session, _) = mock_session(&config, dir.path(), 0, vec![Ok(answer.into())]);
+
+        process_prompt("make a file", &config, &mut session);
+        assert_eq!(session.last_response.as_deref(), Some(answer));
+        assert_eq!(fs::read_to_string(dir.path().join("made.txt")).unwrap(), "made\n");
+    }
+
+    #[test]
+    fn process_prompt_records_nothing_when_ollama_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--execute", "never"]);
+        let failed = CodexError::Other("Error: llama runner process has terminated: exit status 2".into());
+        let (mut session, calls) = mock_session(&config, dir.path(), 3, vec![Err(failed)]);
+
+        process_prompt("hi", &config, &mut session);
+        // Only an unreachable daemon is worth retrying
+        assert_eq!(calls.get(), 1);
+        assert!(session.last_response.is_none());
+        assert!(session.conversation.turns.is_empty());
+    }
+
+    #[test]
+    fn process_prompt_records_nothing_on_a_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--execute", "never"]);
+        let timeout = CodexError::Timeout(Duration::from_secs(30));
+        let (mut session, calls) = mock_session(&config, dir.path(), 3, vec![Err(timeout)]);
+
+        process_prompt("hi", &config, &mut session);
+        assert_eq!(calls.get(), 1);
+        assert!(session.last_response.is_none());
+    }
+
+    #[test]
+    fn process_prompt_retries_until_ollama_answers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--execute", "never"]);
+        let refused = || Err(CodexError::ModelUnavailable("connection refused".into()));
+        let (mut session, calls) = mock_session(&config, dir.path(), 3, vec![refused(), refused(), Ok("Up now.\n".into())]);
+
+        process_prompt("hi", &config, &mut session);
+        assert_eq!(calls.get(), 3);
+        assert_eq!(session.last_response.as_deref(), Some("Up now.\n"));
+    }
+
+    #[test]
+    fn process_prompt_gives_up_when_ollama_stays_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--execute", "never"]);
+        let refused = || Err(CodexError::ModelUnavailable("connection refused".into()));
+        let (mut session, calls) = mock_session(&config, dir.path(), 1, vec![refused(), refused(), Ok("late".into())]);
+
+        process_prompt("hi", &config, &mut session);
+        assert_eq!(calls.get(), 2);
+        assert!(session.last_response.is_none());
+    }
+
+    /// A PATH with just `programs` on it
+    fn installed(programs: &'static [&'static str]) -> impl Fn(&str) -> bool {
+        move |program| programs.contains(&program)
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn fixes_python_to_python3_keeping_the_rest() {
+        let fixed = fix_command_with("python app.py | grep \"a b\" && echo ok > out.txt", &installed(&["python3"]));
+        assert_eq!(fixed.unwrap(), "python3 app.py | grep \"a b\" && echo ok > out.txt");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn fixes_pip_to_pip3_or_python3_m_pip() {
+        assert_eq!(fix_command_with("pip install 'requests>=2'", &installed(&["pip3"])).unwrap(), "pip3 install 'requests>=2'");
+        assert_eq!(fix_command_with("pip3 list", &installed(&["python3"])).unwrap(), "python3 -m pip list");
+        assert_eq!(fix_command_with("npm start", &installed(&["npx"])).unwrap(), "npx start");
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn no_fix_when_the_program_exists_or_is_unknown() {
+        assert!(fix_command_with("python app.py", &installed(&["python", "python3"])).is_err());
+        assert!(fix_command_with("pip install x", &installed(&[])).is_err());
+        let err = fix_command_with("cargo build", &installed(&["python3"])).unwrap_err();
+        assert_eq!(err.to_string(), "No known fix for cargo");
+        assert!(fix_command_with("   ", &installed(&["python3"])).is_err());
+    }
+
+    #[test]
+    fn refuses_home_or_root_as_an_explicit_workdir() {
+        let home = tempfile::tempdir().unwrap();
+        let home = fs::canonicalize(home.path()).unwrap();
+        let err = Workspace::resolve_from(Some(&home.to_string_lossy()), Path::new("/tmp"), Some(&home), false);
+        assert!(err.is_err_and(|e| e.to_string().contains("home directory")));
+        let err = Workspace::resolve_from(Some("/"), Path::new("/tmp"), Some(&home), false);
+        assert!(err.is_err_and(|e| e.to_string().contains("filesystem root")));
+    }
+
+    #[test]
+    fn refuses_home_or_root_as_the_cwd() {
+        let home = tempfile::tempdir().unwrap();
+        let home = fs::canonicalize(home.path()).unwrap();
+        let err = Workspace::resolve_from(None, &home, Some(&home), false);
+        assert!(err.is_err_and(|e| e.to_string().contains("home directory")));
+        let err = Workspace::resolve_from(None, Path::new("/"), Some(&home), false);
+        assert!(err.is_err_and(|e| e.to_string().contains("filesystem root")));
+    }
+
+    #[test]
+    fn fallback_is_private_and_removed() {
+        let first = Workspace::fallback().unwrap();
+        let second = Workspace::fallback().unwrap();
+        assert_ne!(first.path(), second.path());
+        assert!(first.path().is_dir());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o077, 0);
         }
-        Err(e) => {
-            println!("\n{} {}", style("Error:").bold().red(), style(e).red());
-            println!("{}", style("Please try again or Ctrl+C to exit").dim());
+        let root = first.path().to_path_buf();
+        let clone = first.clone();
+        drop(first);
+        assert!(root.is_dir());
+        drop(clone);
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn allow_unsafe_keeps_home() {
+        let home = tempfile::tempdir().unwrap();
+        let home = fs::canonicalize(home.path()).unwrap();
+        let workspace = Workspace::resolve_from(Some(&home.to_string_lossy()), Path::new("/tmp"), Some(&home), true).unwrap();
+        assert_eq!(workspace.path(), home);
+        let workspace = Workspace::resolve_from(None, &home, Some(&home), true).unwrap();
+        assert_eq!(workspace.path(), home);
+    }
+
+    #[test]
+    fn parses_api_tags() {
+        let body = serde_json::json!({
+            "models": [
+                {
+                    "name": "llama3.2:latest",
+                    "size": 2019393189u64,
+                    "modified_at": "2024-05-01T12:00:00Z"
+                },
+                { "name": "all-minilm:latest", "size": 45960996u64 },
+                { "size": 1 }
+            ]
+        });
+        let models = parse_model_tags(&body);
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].name, "llama3.2:latest");
+        assert_eq!(models[0].size, "2.0 GB");
+        assert!(models[0].modified.starts_with("2024-05-0"), "{}", models[0].modified);
+        assert_eq!(models[1].size, "46 MB");
+        assert_eq!(models[1].modified, "");
+        assert!(parse_model_tags(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn a_block_registering_again_keeps_the_cancel() {
+        static STATE: CancelState = CancelState::new();
+        let batch = Cancellable::register(&STATE, None);
+        STATE.cancelled.store(true, Ordering::SeqCst);
+        // What a block does after a Ctrl-C during its venv setup
+        let run = Cancellable::register(&STATE, Some(1));
+        assert!(run.cancelled());
+        drop(run);
+        assert!(batch.cancelled());
+        assert_eq!(*STATE.running.lock().unwrap(), vec![None]);
+
+        STATE.reset();
+        assert!(!batch.cancelled());
+        drop(batch);
+        assert!(STATE.running.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fences_name_their_file() {
+        let blocks = extract_code_blocks(
+            "```python:src/app.py\nprint(1)\n```\n```python\nprint(2)\n```\n```python:\nprint(3)\n```\n```\nplain\n```\n",
+        );
+        let fields: Vec<_> = blocks.iter().map(|b| (b.lang.as_str(), b.filename.as_deref())).collect();
+        assert_eq!(
+            fields,
+            [("python", Some("src/app.py")), ("python", None), ("python", None), ("", None)]
+        );
+        assert_eq!(blocks[0].code, "print(1)\n");
+    }
+
+    #[test]
+    fn fence_paths_stay_inside_the_directory() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("out");
+        let absolute = root.path().join("abs.py");
+        let response = format!(
+            "```python:../escape.py\nprint(1)\n```\n```python:{}\nprint(2)\n```\n```python:pkg/mod.py\nprint(3)\n```\n",
+            absolute.display()
+        );
+        let written = extract_blocks_to(&response, &dir).unwrap();
+        assert_eq!(written, [dir.join("block1.py"), dir.join("block2.py"), dir.join("pkg/mod.py")]);
+        assert!(!root.path().join("escape.py").exists());
+        assert!(!absolute.exists());
+        assert!(!stays_inside("../escape.py") && !stays_inside("/etc/passwd"));
+        assert!(stays_inside("pkg/mod.py"));
+    }
+
+    #[test]
+    fn parses_model_options() {
+        assert_eq!(parse_model_option("seed=42"), Ok(("seed".to_string(), serde_json::json!(42))));
+        assert_eq!(parse_model_option(" top_p = 0.9 "), Ok(("top_p".to_string(), serde_json::json!(0.9))));
+        assert_eq!(parse_model_option("stop=###"), Ok(("stop".to_string(), serde_json::json!(["###"]))));
+        assert_eq!(parse_model_option("numa=true"), Ok(("numa".to_string(), serde_json::json!(true))));
+        assert_eq!(parse_model_option("custom=text"), Ok(("custom".to_string(), serde_json::json!("text"))));
+
+        assert_eq!(parse_model_option("seed=1.5").unwrap_err(), "seed must be a whole number, got '1.5'");
+        assert_eq!(parse_model_option("top_p=high").unwrap_err(), "top_p must be a number, got 'high'");
+        for bad in ["seed", "=5", " =5", ""] {
+            assert!(parse_model_option(bad).unwrap_err().starts_with("expected key=value"), "{}", bad);
         }
     }
-}
 
-fn fix_command(command: &str) -> Result<String, String> {
-    let parts: Vec<&str> = command.split_whitespace().collect();
-    if parts.is_empty() {
-        return Err("Empty command".to_string());
+    #[test]
+    fn cli_backend_refuses_options() {
+        let options = ModelOptions { max_tokens: None, temperature: Some(0.2), extra: Vec::new() };
+        let Err(err) = stream_model("hi", "llama3.2", &options, |_| {}) else {
+            panic!("ran ollama with options set");
+        };
+        assert!(err.to_string().contains("--backend http"), "{}", err);
     }
 
-    // Common command fixes
-    let fixed = match parts[0] {
-        "python" if cfg!(windows) => "py",
-        "python3" if cfg!(windows) => "py",
-        "pip" if cfg!(windows) => "py -m pip",
-        "npm" if !Command::new("npm").output().is_ok() => "npx",
-        _ => parts[0],
-    };
+    #[test]
+    fn strips_think_sections() {
+        assert_eq!(strip_thinking("<think>\nplan\n</think>\nanswer\n"), "answer\n");
+        assert_eq!(strip_thinking("a<think>b</think>c<think>d</think>e"), "ace");
+        assert_eq!(strip_thinking("no tags\n"), "no tags\n");
+        // Unclosed, the thinking may be the answer
+        assert_eq!(strip_thinking("<think>\nmaybe the answer\n"), "\nmaybe the answer\n");
+    }
 
-    let mut fixed_parts = vec![fixed];
-    fixed_parts.extend_from_slice(&parts[1..]);
-    Ok(fixed_parts.join(" "))
-}
+    #[test]
+    fn renders_think_sections() {
+        assert_eq!(render("<think>\nplan\n</think>\nanswer", OutputFormat::Markdown), "answer\n");
+        assert_eq!(render("a<think>b</think>c", OutputFormat::Markdown), "a\nc\n");
+        assert_eq!(render("<think>\nmaybe the answer", OutputFormat::Markdown), "> maybe the answer\n");
+        // Plain and json output keep the tags
+        assert_eq!(render("<think>plan</think>answer", OutputFormat::Plain), "<think>plan</think>answer\n");
+    }
 
-fn main() {
-    let args = Args::parse();
-    
-    if !args.raw {
-        print_banner();
-        println!("{}", style("Type your prompt and hit Enter; Ctrl+C to exit.").dim());
-        println!("{}", style("For system commands, prefix with ! (e.g. !ls)").dim());
-        println!("{}", style("─────────────────────────────").dim());
-        
-        // Show initial setup animation
-        show_animated_message("Initializing CodexCLI...", Duration::from_secs(1));
+    #[test]
+    fn json_history_leaves_out_thinking() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&["--format", "json"]);
+        let reply = "<think>\nplan\n</think>\nanswer\n".to_string();
+        let (mut session, _) = mock_session(&config, dir.path(), 0, vec![Ok(reply)]);
+        process_prompt("question", &config, &mut session);
+        assert_eq!(session.last_response.as_deref(), Some("answer\n"));
     }
 
-    loop {
-        let prompt = get_user_input();
-        if prompt.is_empty() {
-            continue;
+    #[test]
+    fn normalizes_language_tags() {
+        for (tag, lang) in [
+            ("python", Lang::Python),
+            ("py3", Lang::Python),
+            ("shell", Lang::Shell),
+            ("sh", Lang::Shell),
+            ("zsh", Lang::Shell),
+            ("node", Lang::JavaScript),
+            ("js", Lang::JavaScript),
+            ("c++", Lang::Cpp),
+            ("hpp", Lang::Cpp),
+            ("golang", Lang::Go),
+            ("sqlite3", Lang::Sql),
+            ("ipynb", Lang::Notebook),
+            ("  Python ", Lang::Python),
+            ("BASH", Lang::Shell),
+            ("C++", Lang::Cpp),
+        ] {
+            assert_eq!(normalize_language(tag), Some(lang), "{}", tag);
         }
-        process_prompt(&prompt, args.raw, args.workdir.as_deref());
+        // Console fences are transcripts of prompts and output, not scripts
+        for tag in ["console", "text", "", "pythonx"] {
+            assert_eq!(normalize_language(tag), None, "{}", tag);
+        }
+        assert_eq!(normalize_language("Shell").map(Lang::name), Some("bash"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn install_retries_then_reports_the_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo try >> attempts; echo 'no such package' >&2; exit 1"]);
+        let err = install_with_retry(command, "nope", dir.path(), &ProgressBar::hidden()).unwrap_err();
+        assert!(err.to_string().contains("no such package"), "{}", err);
+        let attempts = fs::read_to_string(dir.path().join("attempts")).unwrap();
+        assert_eq!(attempts.lines().count(), INSTALL_ATTEMPTS as usize);
+
+        let command = Command::new("true");
+        assert!(install_with_retry(command, "ok", dir.path(), &ProgressBar::hidden()).is_ok());
+    }
+
+    /// The lines a formatter prints for `lines`, without styling
+    fn formatted(lines: &[&str]) -> Vec<String> {
+        let mut formatter = ResponseFormatter::default();
+        let mut out: Vec<String> = lines.iter().filter_map(|line| formatter.format_line(line)).collect();
+        out.extend(formatter.finish());
+        console::strip_ansi_codes(&out.join("\n")).lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn pads_ragged_table_rows() {
+        let table = formatted(&["| a | b |", "|---|---|", "| 1 |", "| 2 | 3 | 4 |"]);
+        assert_eq!(
+            table,
+            [
+                "┌───┬───┬───┐",
+                "│ a │ b │   │",
+                "├───┼───┼───┤",
+                "│ 1 │   │   │",
+                "│ 2 │ 3 │ 4 │",
+                "└───┴───┴───┘",
+            ]
+        );
+    }
+
+    #[test]
+    fn aligns_columns_from_the_separator_row() {
+        let table = formatted(&["| left | mid | right |", "|:--|:-:|--:|", "| a | b | c |"]);
+        assert_eq!(table[1], "│ left │ mid │ right │");
+        assert_eq!(table[3], "│ a    │  b  │     c │");
+    }
+
+    #[test]
+    fn sizes_columns_to_their_widest_cell() {
+        let table = formatted(&["| name | note |", "|---|---|", "| x | a much longer note |", "| 日本 | y |", "after"]);
+        assert_eq!(table[1], "│ name │ note               │");
+        assert_eq!(table[3], "│ x    │ a much longer note │");
+        assert_eq!(table[4], "│ 日本 │ y                  │");
+        let widths: Vec<usize> = table[..6].iter().map(|line| console::measure_text_width(line)).collect();
+        assert!(widths.iter().all(|w| *w == widths[0]), "{:?}", widths);
+        assert_eq!(table[6], "after");
+    }
+
+    #[test]
+    fn a_lone_pipe_line_is_prose() {
+        assert_eq!(formatted(&["a | b", "next"]), ["a | b", "next"]);
+        assert_eq!(formatted(&["a | b"]), ["a | b"]);
+    }
+
+    #[test]
+    fn attaches_referenced_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.rs");
+        fs::write(&file, "fn a() {}\n").unwrap();
+        let path = file.display().to_string();
+
+        let (text, attached) = expand_file_references(&format!("explain @{}, and (@{}) @someone", path, path));
+        assert_eq!(attached, std::slice::from_ref(&path));
+        assert_eq!(
+            text,
+            format!("explain {}, and ({}) @someone\n\n{}:\n```rs\nfn a() {{}}\n```", path, path, path)
+        );
+
+        let (text, attached) = expand_file_references("ask @someone about @missing.rs");
+        assert_eq!(text, "ask @someone about @missing.rs");
+        assert!(attached.is_empty());
+    }
+
+    #[test]
+    fn skips_files_over_the_attachment_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("big.txt");
+        fs::write(&file, "x".repeat(MAX_ATTACHMENT_BYTES as usize + 1)).unwrap();
+        let path = file.display().to_string();
+        let (text, attached) = expand_file_references(&format!("read @{}", path));
+        assert_eq!(text, format!("read {}", path));
+        assert!(attached.is_empty());
+    }
+
+    #[test]
+    fn reads_notebook_code_cells() {
+        let notebook = r##"{
+            "cells": [
+                {"cell_type": "markdown", "source": ["# Title"]},
+                {"cell_type": "code", "source": ["import math\n", "x = 2\n"]},
+                {"cell_type": "code", "source": "print(x)"},
+                {"cell_type": "code", "source": ["  \n"]}
+            ]
+        }"##;
+        assert_eq!(notebook_cells(notebook).unwrap(), ["import math\nx = 2\n", "print(x)"]);
+        assert_eq!(notebook_cells(r#"{"cells": []}"#).unwrap(), Vec::<String>::new());
+        assert!(notebook_cells("print(1)").is_none());
+        assert!(notebook_cells(r#"{"metadata": {}}"#).is_none());
+    }
+
+    #[test]
+    fn notebook_script_comments_out_magics() {
+        let script = notebook_script(&["%matplotlib inline\n!pip install x\nx = 1".to_string(), "print(x)".to_string()]);
+        assert!(script.contains(r##"cells = ["# %matplotlib inline\n# !pip install x\nx = 1","print(x)"]"##), "{}", script);
+        assert!(script.contains("raise SystemExit(1)"));
+    }
+
+    #[test]
+    fn names_html_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        assert_eq!(html_file_name("<!-- file: site/Index.html -->\n<p>hi</p>", dir, &[]), "Index.html");
+        assert_eq!(html_file_name("<html><TITLE>My Page!</TITLE></html>", dir, &[]), "my-page.html");
+        assert_eq!(html_file_name("<title>../../etc</title>", dir, &[]), "etc.html");
+        let stamped = html_file_name("<p>no title</p>", dir, &[]);
+        assert!(stamped.starts_with("page-") && stamped.ends_with(".html"), "{}", stamped);
+    }
+
+    #[test]
+    fn html_names_avoid_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let page = "<title>Demo</title>";
+        fs::write(dir.join("demo.html"), "someone else's").unwrap();
+        assert_eq!(html_file_name(page, dir, &[]), "demo-2.html");
+        fs::write(dir.join("demo-2.html"), "").unwrap();
+        assert_eq!(html_file_name(page, dir, &[]), "demo-3.html");
+        // A page this session wrote is replaced
+        assert_eq!(html_file_name(page, dir, &[dir.join("demo.html")]), "demo.html");
     }
 }
@@ -0,0 +1,122 @@
+use log::debug;
+use std::{fs, path::PathBuf};
+
+use crate::{config::config_dir, error::CodexError};
+
+/// Directory of prompt templates, one file per template named after it,
+/// e.g. `templates/refactor.txt` for `!tpl refactor`
+fn templates_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("templates"))
+}
+
+/// Names of the saved templates, sorted
+pub fn list_templates() -> Result<Vec<String>, CodexError> {
+    let dir = templates_dir().ok_or_else(|| CodexError::from("Could not determine home directory"))?;
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut names: Vec<String> = fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+        .collect();
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+/// Read the template called `name`, with or without an extension
+fn load_template(name: &str) -> Result<String, CodexError> {
+    let dir = templates_dir().ok_or_else(|| CodexError::from("Could not determine home directory"))?;
+    let path = fs::read_dir(&dir)
+        .ok()
+        .and_then(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .find(|path| path.is_file() && path.file_stem().is_some_and(|stem| stem == name))
+        })
+        .ok_or_else(|| format!("No template '{}' in {}", name, dir.display()))?;
+    debug!("loading template {}", path.display());
+    fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Fill the template called `name` with `input`
+pub fn expand_template(name: &str, input: &str) -> Result<String, CodexError> {
+    fill_template(name, &load_template(name)?, input)
+}
+
+/// Fill a template: `{input}` becomes all of `input`, and `{1}`, `{2}`, ...
+/// its words (quotes group words, as in a shell). A template with neither
+/// gets the input appended after a blank line.
+fn fill_template(name: &str, template: &str, input: &str) -> Result<String, CodexError> {
+    let input = input.trim();
+    let words = shell_words::split(input).unwrap_or_else(|_| input.split_whitespace().map(String::from).collect());
+
+    let mut prompt = String::new();
+    let mut filled = false;
+    let mut rest = template.trim_end();
+    while let Some(start) = rest.find('{') {
+        prompt.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let key = after.find('}').map(|end| &after[..end]).unwrap_or("");
+        let value = match key {
+            "input" => Some(input),
+            _ if !key.is_empty() && key.chars().all(|c| c.is_ascii_digit()) => {
+                let word = key.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|i| words.get(i));
+                let word = word.ok_or_else(|| {
+                    format!("Template '{}' has {{{}}} but got {} argument(s)", name, key, words.len())
+                })?;
+                Some(word.as_str())
+            }
+            _ => None,
+        };
+        match value {
+            Some(value) => {
+                prompt.push_str(value);
+                filled = true;
+                rest = &after[key.len() + 1..];
+            }
+            None => {
+                prompt.push('{');
+                rest = after;
+            }
+        }
+    }
+    prompt.push_str(rest);
+
+    if !filled && !input.is_empty() {
+        prompt = format!("{}\n\n{}", prompt, input);
+    }
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_input_and_numbered_words() {
+        let filled = fill_template("t", "Rename {1} to {2} in:\n{input}\n", "old \"new name\" x").unwrap();
+        assert_eq!(filled, "Rename old to new name in:\nold \"new name\" x");
+    }
+
+    #[test]
+    fn appends_input_without_placeholders() {
+        assert_eq!(fill_template("t", "Review this.\n", " code ").unwrap(), "Review this.\n\ncode");
+        assert_eq!(fill_template("t", "Review this.", "").unwrap(), "Review this.");
+    }
+
+    #[test]
+    fn leaves_other_braces_alone() {
+        let filled = fill_template("t", "fn main() {} {x} {input", "hi").unwrap();
+        assert_eq!(filled, "fn main() {} {x} {input\n\nhi");
+        assert_eq!(fill_template("t", "{input} {", "hi").unwrap(), "hi {");
+    }
+
+    #[test]
+    fn a_missing_word_is_an_error() {
+        let err = fill_template("t", "{1} and {3}", "a b").unwrap_err();
+        assert_eq!(err.to_string(), "Template 't' has {3} but got 2 argument(s)");
+        assert!(fill_template("t", "{0}", "a").is_err());
+    }
+}
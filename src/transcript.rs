@@ -0,0 +1,98 @@
+use chrono::Local;
+use serde::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{config_dir, OutputFormat},
+    error::CodexError,
+    render,
+};
+
+/// Directory holding one markdown transcript per day
+fn sessions_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("sessions"))
+}
+
+/// One prompt/response exchange as markdown. The response is kept verbatim
+/// so its code fences survive and replay renders it like the live display.
+fn transcript_entry(timestamp: &str, model: &str, prompt: &str, response: &str) -> String {
+    format!(
+        "## {} ({})\n\n**Prompt:**\n\n{}\n\n**Response:**\n\n{}\n\n---\n\n",
+        timestamp,
+        model,
+        prompt.trim(),
+        response.trim_end()
+    )
+}
+
+/// Append an exchange to today's transcript, e.g. `sessions/2024-05-01.md`
+pub fn append_transcript(model: &str, prompt: &str, response: &str) -> Result<(), CodexError> {
+    let dir = sessions_dir().ok_or_else(|| CodexError::from("Could not determine home directory"))?;
+    fs::create_dir_all(&dir)?;
+
+    let now = Local::now();
+    let path = dir.join(format!("{}.md", now.format("%Y-%m-%d")));
+    let entry = transcript_entry(&now.format("%Y-%m-%d %H:%M:%S").to_string(), model, prompt, response);
+
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(entry.as_bytes()))
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// One code block from a response, as a line of `--export-blocks` output
+#[derive(Serialize)]
+pub struct BlockRecord {
+    pub timestamp: String,
+    pub prompt: String,
+    pub language: String,
+    pub code: String,
+    pub executed: bool,
+    /// Captured output of a successful run, when there was any
+    pub result: Option<String>,
+    pub error: Option<String>,
+}
+
+impl BlockRecord {
+    /// A block that hasn't been run (yet)
+    pub fn new(prompt: &str, language: &str, code: &str) -> Self {
+        BlockRecord {
+            timestamp: Local::now().to_rfc3339(),
+            prompt: prompt.to_string(),
+            language: language.to_string(),
+            code: code.to_string(),
+            executed: false,
+            result: None,
+            error: None,
+        }
+    }
+}
+
+/// Append one JSON line per block to `path`
+pub fn export_blocks(path: &Path, records: &[BlockRecord]) -> Result<(), CodexError> {
+    let mut lines = String::new();
+    for record in records {
+        let line = serde_json::to_string(record).map_err(|e| format!("Failed to encode block: {}", e))?;
+        lines.push_str(&line);
+        lines.push('\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(lines.as_bytes()))
+        .map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Print a saved transcript the way live responses are printed in `format`
+pub fn replay_transcript(path: &Path, format: OutputFormat) -> Result<(), CodexError> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    print!("{}", render(&contents, format));
+    Ok(())
+}
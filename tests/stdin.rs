@@ -0,0 +1,43 @@
+//! Running the interactive loop with nothing on stdin
+
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use wait_timeout::ChildExt;
+
+/// Start the binary in a throwaway home, against a server that isn't
+/// there, so it never needs the `ollama` CLI or a model
+fn codexcli(home: &tempfile::TempDir) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_codex_cli"));
+    command
+        .args(["--backend", "http", "--no-banner"])
+        .env("HOME", home.path())
+        .env("USERPROFILE", home.path())
+        .env("OLLAMA_HOST", "127.0.0.1:9")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    command
+}
+
+/// Wait for the child to leave the prompt loop by itself, killing it if
+/// it hangs
+fn exits_cleanly(mut child: std::process::Child) {
+    match child.wait_timeout(Duration::from_secs(20)).unwrap() {
+        Some(status) => assert!(status.success(), "exited with {}", status),
+        None => {
+            let _ = child.kill();
+            panic!("still running after stdin was closed");
+        }
+    }
+    let mut output = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert!(output.contains("Goodbye"), "{}", output);
+}
+
+#[test]
+fn closed_stdin_exits() {
+    let home = tempfile::tempdir().unwrap();
+    let child = codexcli(&home).stdin(Stdio::null()).spawn().unwrap();
+    exits_cleanly(child);
+}